@@ -0,0 +1,119 @@
+#[macro_use]
+extern crate criterion;
+extern crate arbtree;
+extern crate rand;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+
+use arbtree::Tree;
+use criterion::{BatchSize, BenchmarkId, Criterion};
+use rand::{Rng, SeedableRng, XorShiftRng};
+#[cfg(feature = "rayon")]
+use rayon::iter::ParallelIterator;
+
+const SIZES: [u64; 3] = [1_000, 100_000, 10_000_000];
+
+// Keys `0..len` in a shuffled order that is the same on every run, so results are comparable.
+fn shuffled_keys(len: u64) -> Vec<u64> {
+    let mut keys: Vec<u64> = (0..len).collect();
+    XorShiftRng::from_seed([1, 2, 3, 4]).shuffle(&mut keys);
+    keys
+}
+
+fn tree_of(len: u64) -> Tree<u64, u64> {
+    shuffled_keys(len).into_iter().map(|key| (key, key)).collect()
+}
+
+// Enough arithmetic per entry that a fold measures the work being spread across threads rather than
+// just the traversal.
+fn weigh(value: u64) -> u64 {
+    (0..50).fold(value, |acc, _| acc.wrapping_mul(6364136223846793005).wrapping_add(1))
+}
+
+fn fold(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fold");
+    group.sample_size(10);
+    let tree = tree_of(4_000_000);
+    group.bench_function("sequential", |b| {
+        b.iter(|| tree.iter().fold(0u64, |sum, (_, &value)| sum.wrapping_add(weigh(value))))
+    });
+    #[cfg(feature = "rayon")]
+    group.bench_function("par_iter", |b| {
+        b.iter(|| {
+            tree.par_iter()
+                .fold(|| 0u64, |sum, (_, &value)| sum.wrapping_add(weigh(value)))
+                .reduce(|| 0, u64::wrapping_add)
+        })
+    });
+    #[cfg(feature = "rayon")]
+    group.bench_function("par_fold", |b| {
+        b.iter(|| tree.par_fold(0u64, |sum, (_, &value)| sum.wrapping_add(weigh(value)),
+                                u64::wrapping_add))
+    });
+    group.finish()
+}
+
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    for &len in &SIZES {
+        let tree = tree_of(len);
+        group.bench_with_input(BenchmarkId::from_parameter(len), &len, |b, &len| {
+            let mut rng = XorShiftRng::from_seed([5, 6, 7, 8]);
+            b.iter(|| tree.insert(rng.gen_range(0, len * 2), 0))
+        });
+    }
+    group.finish()
+}
+
+fn remove(c: &mut Criterion) {
+    let mut group = c.benchmark_group("remove");
+    for &len in &SIZES {
+        let tree = tree_of(len);
+        group.bench_with_input(BenchmarkId::from_parameter(len), &len, |b, &len| {
+            let mut rng = XorShiftRng::from_seed([5, 6, 7, 8]);
+            b.iter(|| tree.remove(&rng.gen_range(0, len)))
+        });
+    }
+    group.finish()
+}
+
+fn get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    for &len in &SIZES {
+        let tree = tree_of(len);
+        group.bench_with_input(BenchmarkId::from_parameter(len), &len, |b, &len| {
+            let mut rng = XorShiftRng::from_seed([5, 6, 7, 8]);
+            b.iter(|| tree.get(&rng.gen_range(0, len * 2)).cloned())
+        });
+    }
+    group.finish()
+}
+
+fn iter(c: &mut Criterion) {
+    let mut group = c.benchmark_group("iter");
+    group.sample_size(10);
+    for &len in &SIZES {
+        let tree = tree_of(len);
+        group.bench_function(BenchmarkId::from_parameter(len), |b| {
+            b.iter(|| tree.iter().fold(0u64, |sum, (_, value)| sum.wrapping_add(*value)))
+        });
+    }
+    group.finish()
+}
+
+fn bulk_build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk_build");
+    group.sample_size(10);
+    for &len in &SIZES {
+        let keys = shuffled_keys(len);
+        group.bench_function(BenchmarkId::from_parameter(len), |b| {
+            b.iter_batched(|| keys.clone(),
+                           |keys| keys.into_iter().map(|key| (key, key)).collect::<Tree<_, _>>(),
+                           BatchSize::LargeInput)
+        });
+    }
+    group.finish()
+}
+
+criterion_group!(benches, fold, insert, remove, get, iter, bulk_build);
+criterion_main!(benches);
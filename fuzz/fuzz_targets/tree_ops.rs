@@ -0,0 +1,33 @@
+// Applies a sequence of inserts and removes decoded from the input by `Op::decode`, checking the
+// red-black invariants and the entries against a `BTreeMap` after every step. The quickcheck
+// properties in `src/tests.rs` drive the same operations.
+//
+// Run with `cargo +nightly fuzz run tree_ops` from the repository root.
+
+#![no_main]
+
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate arbtree;
+
+use arbtree::{Op, Tree};
+use std::collections::BTreeMap;
+
+fuzz_target!(|data: &[u8]| {
+    let (mut tree, mut reference) = (Tree::new(), BTreeMap::new());
+    for op in Op::decode(data) {
+        match op {
+            Op::Insert(key, value) => {
+                tree = tree.insert(key, value);
+                reference.insert(key, value);
+            }
+            Op::Remove(key) => {
+                tree = tree.remove(&key);
+                reference.remove(&key);
+            }
+        }
+        assert!(tree.check_invariants());
+        assert_eq!(tree.len(), reference.len());
+        assert!(tree.iter().eq(reference.iter()));
+    }
+});
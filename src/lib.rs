@@ -1,213 +1,2518 @@
-use std::borrow::Borrow;
-use std::cmp::Ordering;
+#![allow(clippy::redundant_field_names)]
+
+use std::borrow::{Borrow, Cow};
+use std::cmp::{self, Ordering};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::collections::btree_map;
+#[cfg(feature = "rand")]
+use std::collections::BTreeSet;
+use std::error::Error;
 use std::fmt::{self, Debug, Formatter};
-use std::iter::FromIterator;
+use std::hash::{Hash, Hasher};
+use std::iter::{FromIterator, Peekable, Take};
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Bound, Index, Range, RangeBounds, RangeInclusive};
 use std::sync::Arc;
+use std::sync::atomic::{self, AtomicU64};
 
-#[cfg(test)]
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(any(test, feature = "rand"))]
 extern crate rand;
 #[cfg(test)]
 #[macro_use]
 extern crate quickcheck;
 
+#[cfg(feature = "rand")]
+use rand::Rng;
+
+#[cfg(feature = "rayon")]
+mod par;
 #[cfg(test)]
 mod tests;
 
-#[derive(Clone)]
-pub struct Tree<K, V> {
-    root: Link<K, V>,
-}
+#[cfg(feature = "rayon")]
+pub use par::ParIter;
+
+/// A persistent ordered map.
+///
+/// The `M` parameter selects a `Monoid` whose summary is cached at every node, which lets
+/// `range_aggregate` fold any range of entries in O(log n). It defaults to `()`, which caches
+/// nothing.
+pub struct Tree<K, V, M = ()> where M: Monoid<K, V> {
+    root: Link<K, V, M>,
+    version: u64,
+}
+
+// The source of versions for non-empty trees. Empty trees all have version 0.
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+/// A single mutation, as recorded in an operation log and replayed by `Tree::apply`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Op<K, V> {
+    Insert(K, V),
+    Remove(K),
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Op<u8, u8> {
+    /// Decodes a log of operations from raw bytes, such as a fuzzer's input. Each operation takes
+    /// two bytes: the low bit of the first picks an insert or a remove and the rest of it is the
+    /// value, and the second is the key. Keys are a single byte so that removals usually hit. A
+    /// trailing odd byte is ignored.
+    pub fn decode(data: &[u8]) -> Vec<Op<u8, u8>> {
+        data.chunks_exact(2)
+            .map(|pair| {
+                if pair[0] & 1 == 0 {
+                    Op::Insert(pair[1], pair[0] >> 1)
+                } else {
+                    Op::Remove(pair[1])
+                }
+            })
+            .collect()
+    }
+}
+
+/// A key found by `Tree::merge_join`, with its values from whichever of the two trees hold it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MergeSide<'a, K, V, W> where K: 'a, V: 'a, W: 'a {
+    /// The key is in both trees.
+    Both(&'a K, &'a V, &'a W),
+    /// The key is only in the tree `merge_join` was called on.
+    Left(&'a K, &'a V),
+    /// The key is only in the other tree.
+    Right(&'a K, &'a W),
+}
+
+/// An item yielded by `Tree::iter_outside_in`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OutsideIn<'a, K, V> where K: 'a, V: 'a {
+    /// The smallest and the largest of the entries not yet yielded, in that order.
+    Pair((&'a K, &'a V), (&'a K, &'a V)),
+    /// The entry left over in the middle of a tree of odd length, yielded last.
+    Middle((&'a K, &'a V)),
+}
+
+/// The error returned by `Tree::try_insert` when the key is already present.
+#[derive(Clone, PartialEq, Debug)]
+pub struct OccupiedError<V> {
+    /// A clone of the value already in the tree.
+    pub existing: V,
+    /// The value that was not inserted.
+    pub value: V,
+}
+
+impl<V> fmt::Display for OccupiedError<V> where V: Debug {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(formatter,
+               "failed to insert {:?}, key is occupied by {:?}",
+               self.value,
+               self.existing)
+    }
+}
+
+impl<V> Error for OccupiedError<V> where V: Debug {}
+
+/// The error returned by `Tree::try_from_iter` when a key appears more than once.
+#[derive(Clone, PartialEq, Debug)]
+pub struct DuplicateKey<K> {
+    /// The repeated key.
+    pub key: K,
+}
+
+impl<K> fmt::Display for DuplicateKey<K> where K: Debug {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(formatter, "key {:?} appears more than once", self.key)
+    }
+}
+
+impl<K> Error for DuplicateKey<K> where K: Debug {}
+
+/// The reason `Tree::from_structure_checked` rejected its input. Each variant holds the position,
+/// in the level-order input, of the entry at fault.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StructureError {
+    /// The entry's key is a duplicate or cannot be placed in search tree order after the entries
+    /// before it.
+    OrderingViolation(usize),
+    /// The entry is red and has a red child.
+    RedRed(usize),
+    /// The entry's subtrees have different numbers of black nodes on their paths.
+    UnequalBlackHeight(usize),
+    /// The entry is double-black, which only occurs transiently during removal.
+    DoubleBlack(usize),
+}
+
+impl fmt::Display for StructureError {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            StructureError::OrderingViolation(index) => {
+                write!(formatter, "entry {} is out of search tree order", index)
+            }
+            StructureError::RedRed(index) => {
+                write!(formatter, "entry {} is red and has a red child", index)
+            }
+            StructureError::UnequalBlackHeight(index) => {
+                write!(formatter, "the subtrees of entry {} have unequal black heights", index)
+            }
+            StructureError::DoubleBlack(index) => {
+                write!(formatter, "entry {} is double-black", index)
+            }
+        }
+    }
+}
+
+impl Error for StructureError {}
+
+/// The error returned by `Tree::get_bounded` when a lookup needs more comparisons than allowed.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TooDeep {
+    /// The limit that was reached.
+    pub max_steps: usize,
+}
+
+impl fmt::Display for TooDeep {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(formatter, "lookup did not finish within {} comparisons", self.max_steps)
+    }
+}
+
+impl Error for TooDeep {}
+
+/// An associative operation with an identity element, used to summarize the entries of a tree.
+///
+/// Each node caches the summary of its subtree, computed as
+/// `combine(combine(left, lift(key, value)), right)`. `combine` need not be commutative, but it
+/// must be associative for the result not to depend on the shape of the tree.
+pub trait Monoid<K, V> {
+    type Summary: Clone;
+
+    /// The summary of no entries.
+    fn identity() -> Self::Summary;
+
+    /// The summary of a single entry.
+    fn lift(key: &K, value: &V) -> Self::Summary;
+
+    /// The summary of the entries summarized by `left` followed by those summarized by `right`.
+    fn combine(left: &Self::Summary, right: &Self::Summary) -> Self::Summary;
+}
+
+impl<K, V> Monoid<K, V> for () {
+    type Summary = ();
+
+    fn identity() {}
+
+    fn lift(_: &K, _: &V) {}
+
+    fn combine(_: &(), _: &()) {}
+}
+
+/// The read-only operations of an ordered map, so that generic code can take either a `Tree` or a
+/// `BTreeMap`.
+///
+/// The two differ in one respect: `BTreeMap::range` panics if the start of the range is past its
+/// end, whereas a `Tree` yields nothing.
+pub trait OrderedMap<K, V> {
+    /// The iterator returned by `iter`.
+    type Iter<'a>: Iterator<Item = (&'a K, &'a V)> where Self: 'a, K: 'a, V: 'a;
+    /// The iterator returned by `range`.
+    type Range<'a>: Iterator<Item = (&'a K, &'a V)> where Self: 'a, K: 'a, V: 'a;
+
+    fn get<Q>(&self, key: &Q) -> Option<&V> where K: Borrow<Q>, Q: ?Sized + Ord;
+
+    /// Iterates over the entries whose keys fall within `range`, in ascending key order.
+    fn range<R>(&self, range: R) -> Self::Range<'_> where R: RangeBounds<K>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates over all entries in ascending key order.
+    fn iter(&self) -> Self::Iter<'_>;
+}
+
+impl<K, V, M> OrderedMap<K, V> for Tree<K, V, M>
+                               where K: Clone + PartialOrd + Ord, V: Clone, M: Monoid<K, V> {
+    type Iter<'a> = Iter<'a, K, V, M> where Self: 'a, K: 'a, V: 'a;
+    type Range<'a> = Take<Iter<'a, K, V, M>> where Self: 'a, K: 'a, V: 'a;
+
+    fn get<Q>(&self, key: &Q) -> Option<&V> where K: Borrow<Q>, Q: ?Sized + Ord {
+        Tree::get(self, key)
+    }
+
+    fn range<R>(&self, range: R) -> Take<Iter<'_, K, V, M>> where R: RangeBounds<K> {
+        let (start, end) = self.range_positions(&range);
+        self.root.iter_from_index(start).take(end - start)
+    }
+
+    fn len(&self) -> usize {
+        Tree::len(self)
+    }
+
+    fn iter(&self) -> Iter<'_, K, V, M> {
+        Tree::iter(self)
+    }
+}
+
+impl<K, V> OrderedMap<K, V> for BTreeMap<K, V> where K: Ord {
+    type Iter<'a> = btree_map::Iter<'a, K, V> where Self: 'a, K: 'a, V: 'a;
+    type Range<'a> = btree_map::Range<'a, K, V> where Self: 'a, K: 'a, V: 'a;
+
+    fn get<Q>(&self, key: &Q) -> Option<&V> where K: Borrow<Q>, Q: ?Sized + Ord {
+        BTreeMap::get(self, key)
+    }
+
+    fn range<R>(&self, range: R) -> btree_map::Range<'_, K, V> where R: RangeBounds<K> {
+        BTreeMap::range(self, range)
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn iter(&self) -> btree_map::Iter<'_, K, V> {
+        BTreeMap::iter(self)
+    }
+}
+
+struct Node<K, V, M> where M: Monoid<K, V> {
+    key: K,
+    value: V,
+    left: Link<K, V, M>,
+    right: Link<K, V, M>,
+    color: Color,
+    size: usize,
+    summary: M::Summary,
+}
+
+impl<K, V, M> Node<K, V, M> where K: Clone, V: Clone, M: Monoid<K, V> {
+    fn recolor(&self, color: Color) -> Link<K, V, M> {
+        Link::node(color,
+                   self.key.clone(),
+                   self.value.clone(),
+                   self.left.clone(),
+                   self.right.clone())
+    }
+}
+
+/// The color of a node, as exposed by `Tree::to_level_order`.
+///
+/// `DoubleBlack` only appears transiently while a removal is rebalancing; no tree holds one.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Color {
+    Red,
+    Black,
+    DoubleBlack,
+}
+
+enum Link<K, V, M> where M: Monoid<K, V> {
+    Empty,
+    Node(Arc<Node<K, V, M>>),
+}
+
+// A subtree along with its black height, as passed around by `join` and the splits.
+type Subtree<K, V, M> = (Link<K, V, M>, usize);
+
+// These are written out by hand because deriving them would require `M: Clone`.
+impl<K, V, M> Clone for Tree<K, V, M> where M: Monoid<K, V> {
+    fn clone(&self) -> Tree<K, V, M> {
+        Tree {
+            root: self.root.clone(),
+            version: self.version,
+        }
+    }
+}
+
+impl<K, V, M> Clone for Node<K, V, M> where K: Clone, V: Clone, M: Monoid<K, V> {
+    fn clone(&self) -> Node<K, V, M> {
+        Node {
+            key: self.key.clone(),
+            value: self.value.clone(),
+            left: self.left.clone(),
+            right: self.right.clone(),
+            color: self.color,
+            size: self.size,
+            summary: self.summary.clone(),
+        }
+    }
+}
+
+impl<K, V, M> Clone for Link<K, V, M> where M: Monoid<K, V> {
+    fn clone(&self) -> Link<K, V, M> {
+        match *self {
+            Link::Empty => Link::Empty,
+            Link::Node(ref node) => Link::Node(node.clone()),
+        }
+    }
+}
+
+impl<K, V, M> Link<K, V, M> where M: Monoid<K, V> {
+    fn node(color: Color, key: K, value: V, left: Link<K, V, M>, right: Link<K, V, M>)
+            -> Link<K, V, M> {
+        let summary = M::combine(&M::combine(&left.summary(), &M::lift(&key, &value)),
+                                 &right.summary());
+        Link::Node(Arc::new(Node {
+            size: left.len() + right.len() + 1,
+            key: key,
+            value: value,
+            left: left,
+            right: right,
+            color: color,
+            summary: summary,
+        }))
+    }
+
+    fn summary(&self) -> M::Summary {
+        match *self {
+            Link::Empty => M::identity(),
+            Link::Node(ref node) => node.summary.clone(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            Link::Empty => 0,
+            Link::Node(ref node) => node.size,
+        }
+    }
+}
+
+impl<K, V> Tree<K, V> where K: Clone + PartialOrd + Ord, V: Clone {
+    pub fn new() -> Tree<K, V> {
+        Tree::default()
+    }
+
+    /// Returns an empty tree, like `new`, but usable in constants and statics. An empty tree
+    /// allocates nothing, so arrays of mostly empty trees cost only their inline size.
+    pub const fn empty() -> Tree<K, V> {
+        Tree {
+            root: Link::Empty,
+            version: 0,
+        }
+    }
+
+    /// Returns a builder that gathers entries from any number of places and builds the tree once.
+    pub fn builder() -> TreeBuilder<K, V> {
+        TreeBuilder::default()
+    }
+}
+
+impl<K, V, M> Tree<K, V, M> where K: Clone + PartialOrd + Ord, V: Clone, M: Monoid<K, V> {
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V> where K: Borrow<Q>, Q: ?Sized + Ord {
+        self.get_by(|node_key| key.cmp(node_key.borrow())).map(|(_, v)| v)
+    }
+
+    /// Borrows the value at `key` if present, or else returns `default()` as an owned value.
+    /// `default` is only called on a miss, so hits clone nothing.
+    pub fn get_cow<'a, Q, F>(&'a self, key: &Q, default: F) -> Cow<'a, V>
+                             where K: Borrow<Q>, Q: ?Sized + Ord, F: FnOnce() -> V {
+        match self.get(key) {
+            Some(value) => Cow::Borrowed(value),
+            None => Cow::Owned(default()),
+        }
+    }
+
+    pub fn get_by<F>(&self, compare: F) -> Option<(&K, &V)>
+                     where F: for<'a> FnMut(&'a K) -> Ordering {
+        self.root.get_by(compare)
+    }
+
+    /// Looks up `key` like `get`, but gives up with `TooDeep` rather than make more than
+    /// `max_steps` comparisons. A valid tree of n entries is at most about 2 log2(n + 1) deep, so a
+    /// lookup that needs more points to a corrupt tree or an inconsistent `Ord`.
+    pub fn get_bounded<Q>(&self, key: &Q, max_steps: usize) -> Result<Option<&V>, TooDeep>
+                          where K: Borrow<Q>, Q: ?Sized + Ord {
+        let (mut link, mut steps) = (&self.root, 0);
+        while let Link::Node(ref node) = *link {
+            if steps == max_steps {
+                return Err(TooDeep { max_steps: max_steps })
+            }
+            steps += 1;
+            link = match key.cmp(node.key.borrow()) {
+                Ordering::Less => &node.left,
+                Ordering::Greater => &node.right,
+                Ordering::Equal => return Ok(Some(&node.value)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the keys of the nodes a `get` of `key` visits, from the root down, each paired with
+    /// how `key` compared to it. The path ends at the match, if any, or else at a leaf.
+    pub fn get_path<Q>(&self, key: &Q) -> Vec<(&K, Ordering)> where K: Borrow<Q>, Q: ?Sized + Ord {
+        let (mut link, mut path) = (&self.root, vec![]);
+        while let Link::Node(ref node) = *link {
+            let ordering = key.cmp(node.key.borrow());
+            path.push((&node.key, ordering));
+            link = match ordering {
+                Ordering::Less => &node.left,
+                Ordering::Greater => &node.right,
+                Ordering::Equal => break,
+            }
+        }
+        path
+    }
+
+    /// Returns the first and last keys whose values equal `target`, or `None` if there are none,
+    /// in O(log n).
+    ///
+    /// This requires that values never decrease as keys increase, since it binary searches on
+    /// values just as `get` does on keys. If they do decrease somewhere, the result is unspecified.
+    pub fn value_range<Q>(&self, target: &Q) -> Option<(&K, &K)>
+                          where V: Borrow<Q>, Q: ?Sized + Ord {
+        // The first node whose value is at least `target`, then the last whose value is at most.
+        let (mut link, mut first) = (&self.root, None);
+        while let Link::Node(ref node) = *link {
+            if node.value.borrow() >= target {
+                first = Some(node);
+                link = &node.left
+            } else {
+                link = &node.right
+            }
+        }
+        let (mut link, mut last) = (&self.root, None);
+        while let Link::Node(ref node) = *link {
+            if node.value.borrow() <= target {
+                last = Some(node);
+                link = &node.right
+            } else {
+                link = &node.left
+            }
+        }
+        match (first, last) {
+            (Some(first), Some(last)) if first.value.borrow() == target => {
+                Some((&first.key, &last.key))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the entries on the path from the root that always descends to the left child, or to
+    /// the right child if `go_right` is set. The last entry is thus the minimum of the tree, or the
+    /// maximum if `go_right` is set. This is an O(log n) walk.
+    pub fn spine(&self, go_right: bool) -> Vec<(&K, &V)> {
+        let (mut link, mut spine) = (&self.root, vec![]);
+        while let Link::Node(ref node) = *link {
+            spine.push((&node.key, &node.value));
+            link = if go_right { &node.right } else { &node.left }
+        }
+        spine
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Tree<K, V, M> {
+        Tree::from_root(self.root.insert(key, value).blacken()).verified("insert")
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Tree<K, V, M> where K: Borrow<Q>, Q: ?Sized + Ord {
+        if self.get(key).is_none() {
+            return self.clone()
+        }
+        let root = match self.root.redden().remove(key) {
+            // A double-black root just means that every path lost a black node.
+            Link::Node(ref node) if node.color.is_double_black() => node.recolor(Color::Black),
+            root => root,
+        };
+        Tree::from_root(root).verified("remove")
+    }
+
+    /// Moves the value at `old` to the key `new`, overwriting any value already at `new`. If `old`
+    /// is absent, returns a clone of `self`. The value is cloned once, out of the node that held
+    /// it.
+    pub fn rename_key<Q>(&self, old: &Q, new: K) -> Tree<K, V, M>
+                         where K: Borrow<Q>, Q: ?Sized + Ord {
+        match self.get(old) {
+            None => self.clone(),
+            Some(value) => self.remove(old).insert(new, value.clone()),
+        }
+    }
+
+    /// Returns a number identifying this tree's contents within the process. Trees with the same
+    /// version are identical, being clones of each other or both empty; trees with different
+    /// versions may still be equal. Every operation that builds a new non-empty tree stamps it
+    /// with a version no tree has had before.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn len(&self) -> usize {
+        self.root.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.to_option().is_none()
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a, K, V, M> {
+        self.root.iter()
+    }
+
+    /// Applies each operation in `ops` in order, returning the final tree.
+    pub fn apply<I>(&self, ops: I) -> Tree<K, V, M> where I: IntoIterator<Item = Op<K, V>> {
+        ops.into_iter().fold(self.clone(), |tree, op| {
+            match op {
+                Op::Insert(key, value) => tree.insert(key, value),
+                Op::Remove(key) => tree.remove(&key),
+            }
+        })
+    }
+
+    /// Lets `f` record any number of edits, then applies them all at once by sorting them and
+    /// merging them with the entries of this tree, in O(n + k log k) for k edits. This beats a
+    /// chain of `insert`s and `remove`s once the edits touch a sizable fraction of the tree.
+    pub fn edit<'a, F>(&self, f: F) -> Tree<K, V, M> where F: FnOnce(&mut Editor<'a, K, V>) {
+        let mut editor = Editor { edits: vec![] };
+        f(&mut editor);
+        if editor.edits.is_empty() {
+            return self.clone()
+        }
+        editor.edits.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut entries = Vec::with_capacity(self.len() + editor.edits.len());
+        let mut existing = self.iter().peekable();
+        let mut edits = editor.edits.into_iter().peekable();
+        while let Some((key, edit)) = edits.next() {
+            while let Some((existing_key, value)) = existing.next_if(|&(other, _)| *other < key) {
+                entries.push((existing_key.clone(), value.clone()))
+            }
+            let mut value = existing.next_if(|&(other, _)| *other == key)
+                                    .map(|(_, value)| value.clone());
+            value = edit.apply(value);
+            while let Some((_, edit)) = edits.next_if(|(other, _)| *other == key) {
+                value = edit.apply(value)
+            }
+            if let Some(value) = value {
+                entries.push((key, value))
+            }
+        }
+        entries.extend(existing.map(|(key, value)| (key.clone(), value.clone())));
+        Tree::from_sorted_vec(entries)
+    }
+
+    /// Returns the `n` entries with the smallest keys, in ascending order, or every entry if there
+    /// are fewer than `n`.
+    pub fn first_n(&self, n: usize) -> Vec<(&K, &V)> {
+        self.rank_range(0, n).collect()
+    }
+
+    /// Returns the `n` entries with the largest keys, or every entry if there are fewer than `n`.
+    /// Like `first_n`, the result is in *ascending* key order, so the largest entry is last.
+    pub fn last_n(&self, n: usize) -> Vec<(&K, &V)> {
+        self.rank_range(self.len().saturating_sub(n), self.len()).collect()
+    }
+
+    /// Returns up to `n` entries with keys greater than or equal to `start`, in ascending order.
+    /// Fewer than `n` are returned if the tree runs out first.
+    pub fn range_take<Q>(&self, start: &Q, n: usize) -> Vec<(&K, &V)>
+                         where K: Borrow<Q>, Q: ?Sized + Ord {
+        let mut entries = Vec::with_capacity(cmp::min(n, self.len()));
+        entries.extend(self.root.iter_from(start).take(n));
+        entries
+    }
+
+    /// Returns the keys and values as two index-aligned vectors, in key order.
+    pub fn collect_refs(&self) -> (Vec<&K>, Vec<&V>) {
+        let mut keys = Vec::with_capacity(self.len());
+        let mut values = Vec::with_capacity(self.len());
+        for (key, value) in self.iter() {
+            keys.push(key);
+            values.push(value)
+        }
+        (keys, values)
+    }
+
+    /// Iterates over the entries whose keys start with `prefix`, in key order. Those keys sort
+    /// contiguously from `prefix` itself, so this seeks there and stops at the first key that
+    /// does not match, in O(log n + k), without needing an upper bound.
+    pub fn prefix_str<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a K, &'a V)> + 'a
+                          where K: Borrow<str> {
+        self.root.iter_from(prefix).take_while(move |&(key, _)| key.borrow().starts_with(prefix))
+    }
+
+    /// Returns the entry at in-order position `index`, that is, the entry with `index` smaller
+    /// keys, in O(log n).
+    pub fn select(&self, index: usize) -> Option<(&K, &V)> {
+        self.root.select(index).map(|node| (&node.key, &node.value))
+    }
+
+    /// Returns the entry just before in-order position `index`, or `None` if there is none, in
+    /// O(log n). `before(len)` is the last entry.
+    pub fn before(&self, index: usize) -> Option<(&K, &V)> {
+        self.select(index.checked_sub(1)?)
+    }
+
+    /// Returns the entry just after in-order position `index`, or `None` if there is none, in
+    /// O(log n).
+    pub fn after(&self, index: usize) -> Option<(&K, &V)> {
+        self.select(index.checked_add(1)?)
+    }
+
+    /// Returns the range of in-order positions holding `key`, in O(log n). Keys are unique, so this
+    /// has length 1 if the key is present. If it is absent, the range is empty and starts at the
+    /// position the key would occupy if inserted.
+    pub fn positions_of<Q>(&self, key: &Q) -> Range<usize> where K: Borrow<Q>, Q: ?Sized + Ord {
+        let (position, found) = self.root.rank(key);
+        position..(position + found as usize)
+    }
+
+    /// Returns the in-order position of the first entry whose key is greater than or equal to
+    /// `key`, in O(log n): the position of `lower_bound(key)`, or `len` if there is none. This is
+    /// the rank of `key`, the number of keys less than it, and so equals `positions_of(key).start`.
+    pub fn position_at_or_after<Q>(&self, key: &Q) -> usize where K: Borrow<Q>, Q: ?Sized + Ord {
+        self.root.rank(key).0
+    }
+
+    /// Returns the median entry in O(log n), or `None` if the tree is empty. When the length is
+    /// even, this is the lower of the two middle entries, at position `len / 2 - 1`.
+    pub fn median(&self) -> Option<(&K, &V)> {
+        self.select(self.len().checked_sub(1)? / 2)
+    }
+
+    /// Returns a tree in which the entry at in-order position `index` has the value `value`,
+    /// keeping its key. Only the path to that entry is copied, in O(log n).
+    ///
+    /// Panics if `index >= len`.
+    pub fn set_at(&self, index: usize, value: V) -> Tree<K, V, M> {
+        assert!(index < self.len(), "index {} out of bounds for a tree of length {}", index,
+                self.len());
+        Tree::from_root(self.root.set_at(index, value))
+    }
+
+    /// Removes the entry at in-order position `index`, returning the new tree and a clone of the
+    /// removed entry, in O(log n). If `index >= len`, returns `self` unchanged and `None`.
+    pub fn remove_at(&self, index: usize) -> (Tree<K, V, M>, Option<(K, V)>) {
+        match self.select(index) {
+            None => (self.clone(), None),
+            Some((key, value)) => (self.remove(key), Some((key.clone(), value.clone()))),
+        }
+    }
+
+    /// Iterates over the entries whose in-order positions fall in `lo..hi`, starting in O(log n).
+    /// `hi` is clamped to `len`, and the iterator is empty if `lo >= hi`.
+    pub fn rank_range<'a>(&'a self, lo: usize, hi: usize)
+                          -> impl Iterator<Item = (&'a K, &'a V)> + 'a {
+        let hi = cmp::min(hi, self.len());
+        self.root.iter_from_index(lo).take(hi.saturating_sub(lo))
+    }
+
+    /// Picks an entry uniformly at random in O(log n), or returns `None` if the tree is empty.
+    #[cfg(feature = "rand")]
+    pub fn sample<R>(&self, rng: &mut R) -> Option<(&K, &V)> where R: Rng {
+        if self.is_empty() {
+            return None
+        }
+        self.select(rng.gen_range(0, self.len()))
+    }
+
+    /// Picks `k` distinct entries uniformly at random, or every entry if there are no more than
+    /// `k`, in O(k log n). The sample is returned in ascending key order.
+    #[cfg(feature = "rand")]
+    pub fn sample_k<R>(&self, k: usize, rng: &mut R) -> Vec<(&K, &V)> where R: Rng {
+        let len = self.len();
+        if k >= len {
+            return self.iter().collect()
+        }
+        // Floyd's algorithm chooses `k` distinct indices with exactly `k` random draws.
+        let mut indices = BTreeSet::new();
+        for upper in (len - k)..len {
+            let index = rng.gen_range(0, upper + 1);
+            if !indices.insert(index) {
+                indices.insert(upper);
+            }
+        }
+        indices.into_iter().map(|index| self.select(index).unwrap()).collect()
+    }
+
+    /// Splits the tree by in-order position into the first `index` entries and the rest, in
+    /// O(log n). An `index` past the end is clamped to `len`, so the second tree is empty.
+    pub fn split_at(&self, index: usize) -> (Tree<K, V, M>, Tree<K, V, M>) {
+        if index >= self.len() {
+            return (self.clone(), Tree::default())
+        }
+        let ((left, _), (right, _)) = self.root.split_at(self.root.black_height(), index);
+        (Tree::from_root(left), Tree::from_root(right))
+    }
+
+    /// Keeps only the first `len` entries, that is, those with the smallest keys, in O(log n).
+    /// Returns a clone if the tree has no more than `len` entries.
+    pub fn truncate(&self, len: usize) -> Tree<K, V, M> {
+        self.split_at(len).0
+    }
+
+    /// Keeps only the last `len` entries, that is, those with the largest keys, in O(log n).
+    /// Returns a clone if the tree has no more than `len` entries.
+    pub fn keep_largest(&self, len: usize) -> Tree<K, V, M> {
+        if len >= self.len() {
+            return self.clone()
+        }
+        self.split_at(self.len() - len).1
+    }
+
+    /// Drops entries from one end until at most `cap` remain, in O(log n): the largest keys if
+    /// `drop_high`, as `truncate` does, and the smallest otherwise, as `keep_largest` does.
+    /// Returns a clone if the tree already has no more than `cap` entries.
+    pub fn bound_to(&self, cap: usize, drop_high: bool) -> Tree<K, V, M> {
+        if drop_high {
+            self.truncate(cap)
+        } else {
+            self.keep_largest(cap)
+        }
+    }
+
+    /// Inserts `items`, taking a fast path when they are in strictly ascending key order and all
+    /// greater than every key already present, as in append-mostly workloads. The items are then
+    /// bulk-built into a tree and joined onto this one in O(log n + k). Otherwise each is inserted
+    /// in turn, in O(k log (n + k)).
+    pub fn append_sorted<I>(&self, items: I) -> Tree<K, V, M> where I: IntoIterator<Item = (K, V)> {
+        let mut items: Vec<(K, V)> = items.into_iter().collect();
+        let ascending = items.windows(2).all(|pair| pair[0].0 < pair[1].0);
+        let above_max = match (items.first(), self.len().checked_sub(1)) {
+            (None, _) => return self.clone(),
+            (Some(_), None) => true,
+            (Some(first), Some(last)) => self.select(last).unwrap().0 < &first.0,
+        };
+        if !ascending || !above_max {
+            return items.into_iter().fold(self.clone(), |tree, (key, value)| {
+                tree.insert(key, value)
+            })
+        }
+        let rest = items.split_off(1);
+        let (key, value) = items.pop().unwrap();
+        let len = rest.len();
+        let right = Link::from_sorted(&mut rest.into_iter(), len, 0, red_depth(len));
+        let right_height = right.black_height();
+        let left_height = self.root.black_height();
+        let (root, _) = Link::join(self.root.clone(), left_height, key, value, right, right_height);
+        Tree::from_root(root)
+    }
+
+    /// Splits the tree by position into `n` trees over contiguous key ranges, in key order, whose
+    /// sizes differ by at most one. If `n` exceeds `len`, the trailing trees are empty. This takes
+    /// O(n log len).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is 0.
+    pub fn partition_into(&self, n: usize) -> Vec<Tree<K, V, M>> {
+        assert!(n != 0, "partition count must be non-zero");
+        let (mut partitions, mut rest) = (Vec::with_capacity(n), self.clone());
+        for index in 0..n {
+            let len = self.len() / n + (index < self.len() % n) as usize;
+            let (partition, after) = rest.split_at(len);
+            partitions.push(partition);
+            rest = after
+        }
+        partitions
+    }
+
+    /// Returns the first and last keys of the longest run of entries, in key order, whose values
+    /// strictly increase from each entry to the next. If several runs are longest, the first one
+    /// wins. A tree whose values never increase gives a run of a single entry, with both keys the
+    /// same, and an empty tree gives `None`. This is one O(n) pass.
+    pub fn longest_increasing_run(&self) -> Option<(&K, &K)> where V: Ord {
+        let mut entries = self.iter();
+        let (first_key, first_value) = entries.next()?;
+        let (mut best, mut best_len) = ((first_key, first_key), 1);
+        let (mut start, mut len, mut prev) = (first_key, 1, first_value);
+        for (key, value) in entries {
+            if value > prev {
+                len += 1
+            } else {
+                start = key;
+                len = 1
+            }
+            if len > best_len {
+                best = (start, key);
+                best_len = len
+            }
+            prev = value
+        }
+        Some(best)
+    }
+
+    /// Collapses runs of consecutive keys into inclusive ranges, in ascending order, so that the
+    /// keys 1, 2, 3, 5, 6, and 9 become `[1..=3, 5..=6, 9..=9]`.
+    ///
+    /// `next(key)` must return the key immediately after `key`, such as `key + 1` for integers.
+    /// It is only called on keys that have a greater key in the tree after them, so it need not
+    /// handle the largest value of `K`.
+    pub fn key_ranges<F>(&self, next: F) -> Vec<RangeInclusive<K>> where F: Fn(&K) -> K {
+        let mut ranges = vec![];
+        let mut keys = self.iter().map(|(key, _)| key);
+        let mut start = match keys.next() {
+            None => return ranges,
+            Some(first) => first,
+        };
+        let mut end = start;
+        for key in keys {
+            if next(end) != *key {
+                ranges.push(start.clone()..=end.clone());
+                start = key
+            }
+            end = key
+        }
+        ranges.push(start.clone()..=end.clone());
+        ranges
+    }
+
+    /// Groups the entries by `key_fn(key, value)` into a tree of trees, keyed by group. Each inner
+    /// tree holds its group's entries in key order. The inner trees share no nodes with `self` or
+    /// with each other, and each is bulk-built to be fully balanced on its own.
+    pub fn group_into<G, F>(&self, mut key_fn: F) -> Tree<G, Tree<K, V, M>>
+                            where F: FnMut(&K, &V) -> G, G: Clone + Ord {
+        let mut groups: BTreeMap<G, Vec<(K, V)>> = BTreeMap::new();
+        for (key, value) in self.iter() {
+            groups.entry(key_fn(key, value)).or_default().push((key.clone(), value.clone()))
+        }
+        Tree::from_sorted_vec(groups.into_iter()
+                                    .map(|(group, entries)| (group, Tree::from_sorted_vec(entries)))
+                                    .collect())
+    }
+
+    /// Splits the tree into runs of consecutive entries, in key order. Each entry after the first
+    /// is passed to `pred` along with the entry before it, as `pred(prev_key, prev_value, key,
+    /// value)`, and a new run begins *at* that entry if `pred` returns true. An empty tree yields
+    /// no runs.
+    pub fn split_when<F>(&self, mut pred: F) -> Vec<Tree<K, V, M>>
+                         where F: FnMut(&K, &V, &K, &V) -> bool {
+        let (mut segments, mut segment) = (vec![], vec![]);
+        let mut prev: Option<(&K, &V)> = None;
+        for (key, value) in self.iter() {
+            if let Some((prev_key, prev_value)) = prev {
+                if pred(prev_key, prev_value, key, value) {
+                    segments.push(Tree::from_sorted_vec(mem::take(&mut segment)))
+                }
+            }
+            segment.push((key.clone(), value.clone()));
+            prev = Some((key, value))
+        }
+        if !segment.is_empty() {
+            segments.push(Tree::from_sorted_vec(segment))
+        }
+        segments
+    }
+
+    /// Concatenates this tree with `higher` in O(log n) by joining them around the smallest entry
+    /// of `higher`, rather than inserting entries one by one.
+    ///
+    /// Every key in `self` must be less than every key in `higher`. This is checked only in debug
+    /// builds; otherwise, violating it produces a tree whose lookups may miss entries.
+    pub fn concat(&self, higher: &Tree<K, V, M>) -> Tree<K, V, M> {
+        let (pivot, rest) = higher.split_at(1);
+        let (key, value) = match pivot.select(0) {
+            None => return self.clone(),
+            Some((key, value)) => (key.clone(), value.clone()),
+        };
+        debug_assert!(self.len().checked_sub(1).is_none_or(|last| {
+                          self.select(last).unwrap().0 < &key
+                      }),
+                      "concat() requires every key of self to be less than those of higher");
+        let (left_height, right_height) = (self.root.black_height(), rest.root.black_height());
+        let (root, _) =
+            Link::join(self.root.clone(), left_height, key, value, rest.root, right_height);
+        Tree::from_root(root)
+    }
+
+    /// Iterates over the entries in key order along with each one's in-order position, which is
+    /// the same index `select` takes.
+    pub fn enumerate_entries<'a>(&'a self) -> impl Iterator<Item = (usize, &'a K, &'a V)> + 'a {
+        self.iter().enumerate().map(|(index, (key, value))| (index, key, value))
+    }
+
+    /// Returns true if every key in `self` is also a key in `other`. Values are ignored.
+    ///
+    /// This walks both trees in order, so it is O(m + n), and stops at the first missing key.
+    pub fn is_subset(&self, other: &Tree<K, V, M>) -> bool {
+        let mut other_keys = other.iter().map(|(key, _)| key);
+        'outer: for (key, _) in self.iter() {
+            for other_key in &mut other_keys {
+                match other_key.cmp(key) {
+                    Ordering::Less => {}
+                    Ordering::Equal => continue 'outer,
+                    Ordering::Greater => return false,
+                }
+            }
+            return false
+        }
+        true
+    }
+
+    /// Returns true if every key in `other` is also a key in `self`. Values are ignored.
+    pub fn is_superset(&self, other: &Tree<K, V, M>) -> bool {
+        other.is_subset(self)
+    }
+
+    /// Returns true if `self` and `other` have no keys in common. Values are ignored.
+    ///
+    /// Like `is_subset`, this is an O(m + n) walk that stops at the first shared key.
+    pub fn is_disjoint(&self, other: &Tree<K, V, M>) -> bool {
+        let (mut keys, mut other_keys) = (self.iter().peekable(), other.iter().peekable());
+        while let (Some(&(key, _)), Some(&(other_key, _))) = (keys.peek(), other_keys.peek()) {
+            match key.cmp(other_key) {
+                Ordering::Less => { keys.next(); }
+                Ordering::Greater => { other_keys.next(); }
+                Ordering::Equal => return false,
+            }
+        }
+        true
+    }
+
+    /// Iterates in key order over the entries of `self` whose keys are absent from `base`.
+    ///
+    /// The iteration is lazy, and subtrees of `self` that are shared with `base` are skipped
+    /// without being visited, so for a tree derived from `base` by k insertions this visits
+    /// O(k log n) nodes, each in O(log n).
+    pub fn new_entries_since<'a>(&'a self, base: &'a Tree<K, V, M>)
+                                 -> impl Iterator<Item = (&'a K, &'a V)> + 'a {
+        NewEntries {
+            pending: vec![NewEntriesFrame::Subtree(&self.root, &base.root, None, None)],
+        }
+    }
+
+    /// Iterates over the entries from both ends at once, working inward. The `i`th item is the
+    /// pair of the entries at positions `i` and `len - 1 - i`, for each `i < len / 2`. If `len`
+    /// is odd, one more item follows: the middle entry, at position `len / 2`, on its own.
+    pub fn iter_outside_in<'a>(&'a self) -> impl Iterator<Item = OutsideIn<'a, K, V>> + 'a {
+        OutsideInIter {
+            front: self.iter(),
+            back: self.root.rev_iter(),
+            remaining: self.len(),
+        }
+    }
+
+    /// Pairs each entry with the element of `others` at the same in-order position, so the entry
+    /// with the smallest key gets `others[0]`. Alignment is by rank, not by key. Iteration stops at
+    /// the end of the tree or of `others`, whichever comes first.
+    pub fn zip_positional<'a, T>(&'a self, others: &'a [T])
+                                 -> impl Iterator<Item = (&'a K, &'a V, &'a T)> + 'a {
+        self.iter().zip(others).map(|((key, value), other)| (key, value, other))
+    }
+
+    /// Returns true if `self` and `other` share the same root node, or are both empty.
+    ///
+    /// This is an O(1) identity check, not an equality check: two trees holding the same entries
+    /// but built separately return false.
+    pub fn ptr_eq(&self, other: &Tree<K, V, M>) -> bool {
+        self.root.ptr_eq(&other.root)
+    }
+
+    /// Returns the largest subtree that `self` and `other` share by pointer identity, as a tree of
+    /// its own, or `None` if they share no nodes. Ties go to the subtree with the smallest keys.
+    ///
+    /// The two trees are walked together without allocating, so when they share most of their
+    /// structure only the nodes on the paths that differ are visited.
+    pub fn largest_shared_subtree(&self, other: &Tree<K, V, M>) -> Option<Tree<K, V, M>> {
+        let mut largest: Option<&Arc<Node<K, V, M>>> = None;
+        self.root.find_largest_shared((None, None), &other.root, (None, None), &mut largest);
+        largest.map(|node| Tree::from_root(Link::Node(node.clone())))
+    }
+
+    /// Replaces the value at `key` with `new(&old)` if the key is present and `pred(&old)` holds.
+    ///
+    /// Otherwise, returns a tree sharing the same root as `self`. Either way, only one descent is
+    /// made.
+    pub fn replace_if<F, G>(&self, key: K, pred: F, new: G) -> Tree<K, V, M>
+                            where F: FnOnce(&V) -> bool, G: FnOnce(&V) -> V {
+        let root = self.root.modify(&key, |value| {
+            if pred(value) {
+                Some(new(value))
+            } else {
+                None
+            }
+        });
+        match root {
+            Some(root) => Tree::from_root(root),
+            None => self.clone(),
+        }
+    }
+
+    /// Clones the value at `key`, lets `f` mutate the clone, and returns a tree holding the
+    /// mutated value along with `f`'s result.
+    ///
+    /// The original tree is untouched: only the value and the nodes on the path to it are copied.
+    /// If `key` is absent, `f` is not called, and a tree sharing the same root is returned along
+    /// with `None`.
+    pub fn with_mut<Q, F, R>(&self, key: &Q, f: F) -> (Tree<K, V, M>, Option<R>)
+                             where K: Borrow<Q>, Q: ?Sized + Ord, F: FnOnce(&mut V) -> R {
+        let mut result = None;
+        let root = self.root.modify(key, |value| {
+            let mut value = value.clone();
+            result = Some(f(&mut value));
+            Some(value)
+        });
+        match root {
+            Some(root) => (Tree::from_root(root), result),
+            None => (self.clone(), None),
+        }
+    }
+
+    /// Returns the number of entries for which `pred` returns true. This visits every entry.
+    pub fn count_where<F>(&self, mut pred: F) -> usize where F: FnMut(&K, &V) -> bool {
+        self.root.count_where(&mut pred)
+    }
+
+    /// Returns the number of entries whose values satisfy `pred`. Values are not indexed, so this
+    /// is a single O(n) pass over every entry.
+    pub fn count_values_where<F>(&self, mut pred: F) -> usize where F: FnMut(&V) -> bool {
+        self.root.count_where(&mut |_, value| pred(value))
+    }
+
+    /// Feeds the keys, in order, into `state`, ignoring values. Trees with the same set of keys
+    /// hash identically, however they were built.
+    pub fn key_hash<H>(&self, state: &mut H) where K: Hash, H: Hasher {
+        state.write_usize(self.len());
+        for (key, _) in self.iter() {
+            key.hash(state)
+        }
+    }
+
+    /// Counts the entries whose values fall into each bucket, as computed by `bucket`. Values are
+    /// not indexed, so this is a single O(n) scan.
+    pub fn value_histogram<B, F>(&self, mut bucket: F) -> HashMap<B, usize>
+                                 where F: FnMut(&V) -> B, B: Eq + Hash {
+        let mut histogram = HashMap::new();
+        for (_, value) in self.iter() {
+            *histogram.entry(bucket(value)).or_insert(0) += 1
+        }
+        histogram
+    }
+
+    /// Returns the number of distinct values, as judged by their `Eq` and `Hash` impls, in one O(n)
+    /// pass. This is the number of allocations `intern_values` would keep.
+    pub fn distinct_value_count(&self) -> usize where V: Eq + Hash {
+        self.iter().map(|(_, value)| value).collect::<HashSet<_>>().len()
+    }
+
+    /// Returns a tree of the same shape whose values are shared: equal values, as judged by their
+    /// `Eq` and `Hash` impls, become clones of a single `Arc`. This saves memory when few distinct
+    /// values are spread over many entries. Keys are cloned and left in place.
+    pub fn intern_values(&self) -> Tree<K, Arc<V>> where V: Eq + Hash {
+        Tree::from_root(self.root.intern_values(&mut HashMap::new()))
+    }
+
+    /// Returns the numbers of red, black, and double-black nodes, in that order, in O(n).
+    ///
+    /// Double-black nodes exist only in the middle of a removal, so a settled tree with any
+    /// indicates a rebalancing bug.
+    pub fn color_counts(&self) -> (usize, usize, usize) {
+        let mut counts = (0, 0, 0);
+        self.root.count_colors(&mut counts);
+        counts
+    }
+
+    /// Returns an iterator over runs of consecutive entries whose keys project to the same group
+    /// under `key_fn`, in key order.
+    ///
+    /// Only adjacent entries are grouped, as with `itertools::group_by`: if the projection is not
+    /// monotonic in the key, the same group may be yielded more than once.
+    pub fn group_by_key<'a, F, G>(&'a self, key_fn: F) -> GroupByKey<'a, K, V, M, F, G>
+                                  where F: FnMut(&K) -> G, G: PartialEq {
+        GroupByKey {
+            iter: self.iter(),
+            key_fn: key_fn,
+            pending: None,
+        }
+    }
+
+    /// Returns an iterator over the entries in key order, `size` at a time. The last chunk may be
+    /// shorter than `size`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0, like `slice::chunks`.
+    pub fn chunks<'a>(&'a self, size: usize) -> Chunks<'a, K, V, M> {
+        assert!(size != 0, "chunk size must be non-zero");
+        Chunks {
+            iter: self.iter(),
+            size: size,
+        }
+    }
+
+    /// Looks `key` up in `layers` from last to first and returns the first value found, so later
+    /// layers shadow earlier ones.
+    pub fn layered_get<'a, Q>(layers: &'a [Tree<K, V, M>], key: &Q) -> Option<&'a V>
+                              where K: Borrow<Q>, Q: ?Sized + Ord {
+        layers.iter().rev().find_map(|layer| layer.get(key))
+    }
+
+    /// Merges the entries of several trees into one stream in ascending key order, keeping a heap
+    /// of the next entry of each tree.
+    ///
+    /// A key present in more than one tree is yielded once, with the value from the last of those
+    /// trees in `trees`, so later trees shadow earlier ones as in a stack of layers.
+    pub fn merge_iters<'a, I>(trees: I) -> impl Iterator<Item = (&'a K, &'a V)> + 'a
+                              where I: IntoIterator<Item = &'a Tree<K, V, M>>,
+                                    K: 'a,
+                                    V: 'a,
+                                    M: 'a {
+        let mut heads = BinaryHeap::new();
+        for (index, tree) in trees.into_iter().enumerate() {
+            let mut iter = tree.iter();
+            if let Some(entry) = iter.next() {
+                heads.push(MergeHead { entry: entry, index: index, iter: iter })
+            }
+        }
+        Merge {
+            heads: heads,
+        }
+    }
+
+    /// Walks this tree and `other` together in ascending key order, classifying each distinct key
+    /// by which trees contain it. This takes O(m + n), and is the building block of inner and
+    /// outer joins.
+    pub fn merge_join<'a, W, N>(&'a self, other: &'a Tree<K, W, N>)
+                                -> impl Iterator<Item = MergeSide<'a, K, V, W>> + 'a
+                                where W: Clone, N: Monoid<K, W> {
+        MergeJoin {
+            left: self.iter().peekable(),
+            right: other.iter().peekable(),
+        }
+    }
+
+    /// Builds the union of several trees, as in the compaction step of a log-structured merge tree.
+    ///
+    /// When a key is present in more than one tree, the result keeps its value from the tree that
+    /// comes last in `trees`, whatever order the trees' keys or sizes are in. The trees are merged
+    /// with `merge_iters` and bulk-built, in O(N log k) for N entries across k trees.
+    pub fn merge_all<'a, I>(trees: I) -> Tree<K, V, M>
+                            where I: IntoIterator<Item = &'a Tree<K, V, M>>,
+                                  K: 'a,
+                                  V: 'a,
+                                  M: 'a {
+        let entries = Tree::merge_iters(trees).map(|(key, value)| (key.clone(), value.clone()));
+        Tree::from_sorted_vec(entries.collect())
+    }
+
+    /// Returns the first entry whose key is greater than *or equal to* `key`, like C++'s
+    /// `std::map::lower_bound`. This is the "ceiling" of `key`.
+    pub fn lower_bound<Q>(&self, key: &Q) -> Option<(&K, &V)> where K: Borrow<Q>, Q: ?Sized + Ord {
+        self.root.first_above(key, true).map(|node| (&node.key, &node.value))
+    }
+
+    /// Returns the first entry whose key is *strictly* greater than `key`, like C++'s
+    /// `std::map::upper_bound`. This is the successor of `key`.
+    pub fn upper_bound<Q>(&self, key: &Q) -> Option<(&K, &V)> where K: Borrow<Q>, Q: ?Sized + Ord {
+        self.root.first_above(key, false).map(|node| (&node.key, &node.value))
+    }
+
+    /// Returns the entry whose key is nearest to `key` as measured by `dist`, in O(log n). Only the
+    /// closest keys at or below and at or above `key` are considered, so `dist` should grow with
+    /// distance in key order. On a tie, the smaller key wins.
+    pub fn nearest_by<F, D>(&self, key: &K, dist: F) -> Option<(&K, &V)>
+                            where F: Fn(&K, &K) -> D, D: Ord {
+        let below = self.root.last_below(key, true);
+        let above = self.root.first_above(key, true);
+        let node = match (below, above) {
+            (Some(below), Some(above)) if dist(key, &above.key) < dist(key, &below.key) => above,
+            (Some(below), _) => below,
+            (None, above) => above?,
+        };
+        Some((&node.key, &node.value))
+    }
+
+    /// Calls `f` on each entry in key order, stopping as soon as it returns false.
+    pub fn iter_while<F>(&self, mut f: F) where F: FnMut(&K, &V) -> bool {
+        self.root.visit_while(&mut f);
+    }
+
+    /// Collapses runs of adjacent entries into single entries, greedily from left to right. Each
+    /// entry is offered to `mergeable` along with the entry accumulated so far; if it returns
+    /// `Some(merged)`, the two become one entry with the earlier key and the merged value, which
+    /// is then offered the next entry. The result is bulk-built in O(n).
+    pub fn coalesce<F>(&self, mut mergeable: F) -> Tree<K, V, M>
+                       where F: FnMut(&K, &V, &K, &V) -> Option<V> {
+        let mut entries: Vec<(K, V)> = vec![];
+        for (key, value) in self.iter() {
+            if let Some(last) = entries.last_mut() {
+                if let Some(merged) = mergeable(&last.0, &last.1, key, value) {
+                    last.1 = merged;
+                    continue
+                }
+            }
+            entries.push((key.clone(), value.clone()))
+        }
+        Tree::from_sorted_vec(entries)
+    }
+
+    /// Consumes the tree, returning its entries in key order.
+    ///
+    /// Nodes owned only by this tree are moved out of; keys and values are cloned only for nodes
+    /// still shared with other trees.
+    pub fn into_sorted_vec(self) -> Vec<(K, V)> {
+        self.into_iter().collect()
+    }
+
+    /// Consumes the tree, returning its keys in order. Like `into_sorted_vec`, this moves out of
+    /// unshared nodes and clones only what other trees still share.
+    pub fn into_keys(self) -> impl Iterator<Item = K> {
+        self.into_iter().map(|(key, _)| key)
+    }
+
+    /// Consumes the tree, returning its values in key order, moving or cloning them as
+    /// `into_keys` does.
+    pub fn into_values(self) -> impl Iterator<Item = V> {
+        self.into_iter().map(|(_, value)| value)
+    }
+
+    /// Consumes the tree, returning a max-heap of `(value, key)` pairs, so the greatest value is
+    /// popped first and equal values are popped in descending key order. Unshared entries are
+    /// moved rather than cloned, and the heap is built in O(n).
+    pub fn into_binary_heap(self) -> BinaryHeap<(V, K)> where V: Ord {
+        self.into_iter().map(|(key, value)| (value, key)).collect()
+    }
+
+    /// Returns the number of keys within `range`, in O(log n) without visiting them.
+    pub fn count_range<R>(&self, range: R) -> usize where R: RangeBounds<K> {
+        let (start, end) = self.range_positions(&range);
+        end - start
+    }
+
+    /// Returns clones of the values whose keys fall within `range`, in ascending key order. The
+    /// vector is sized up front with `count_range`, and finding the first match takes O(log n).
+    pub fn range_values_vec<R>(&self, range: R) -> Vec<V> where R: RangeBounds<K> {
+        let (start, end) = self.range_positions(&range);
+        let mut values = Vec::with_capacity(end - start);
+        values.extend(self.root.iter_from_index(start).take(end - start).map(|(_, v)| v.clone()));
+        values
+    }
+
+    // The in-order positions of the first key within `range` and of the first key past it.
+    fn range_positions<R>(&self, range: &R) -> (usize, usize) where R: RangeBounds<K> {
+        let start = self.root.count_prefix(&|key| !after_start(range, key));
+        let end = self.root.count_prefix(&|key| before_end(range, key));
+        (start, cmp::max(start, end))
+    }
+
+    /// Returns true if any key falls within `range`, in O(log n) without visiting the matches.
+    pub fn range_has_any<R>(&self, range: R) -> bool where R: RangeBounds<K> {
+        let mut link = &self.root;
+        while let Link::Node(ref node) = *link {
+            if !after_start(&range, &node.key) {
+                link = &node.right
+            } else if !before_end(&range, &node.key) {
+                link = &node.left
+            } else {
+                return true
+            }
+        }
+        false
+    }
+
+    /// Returns the summary of every entry in the tree, in O(1).
+    pub fn aggregate(&self) -> M::Summary {
+        self.root.summary()
+    }
+
+    /// Folds the entries whose keys fall within `range` with the tree's monoid, in O(log n).
+    pub fn range_aggregate<R>(&self, range: R) -> M::Summary where R: RangeBounds<K> {
+        self.root.aggregate_range(&range)
+    }
+
+    /// Folds `f` over the entries whose keys fall within `range`, in key order, visiting only
+    /// subtrees that overlap it, in O(log n + k). Endpoints are treated as in `retain_range`.
+    pub fn fold_range<R, B, F>(&self, range: R, init: B, mut f: F) -> B
+                               where R: RangeBounds<K>, F: FnMut(B, &K, &V) -> B {
+        let mut accumulator = Some(init);
+        self.root.visit_range(&range, &mut |key, value| {
+            accumulator = Some(f(accumulator.take().unwrap(), key, value))
+        });
+        accumulator.unwrap()
+    }
+
+    /// Returns a tree containing only the entries whose keys fall within `range`. Each endpoint is
+    /// inclusive or exclusive exactly as `range` specifies, so `a..b` keeps `a` but drops `b`.
+    ///
+    /// This splits the tree at the range's endpoints, so it takes O(log n), and all but O(log n)
+    /// of the nodes of the result are shared with `self`.
+    pub fn retain_range<R>(&self, range: R) -> Tree<K, V, M> where R: RangeBounds<K> {
+        let height = self.root.black_height();
+        let (_, (rest, rest_height)) =
+            self.root.split_where(height, &|key| !after_start(&range, key));
+        let ((inside, _), _) = rest.split_where(rest_height, &|key| before_end(&range, key));
+        Tree::from_root(inside)
+    }
+
+    /// Returns a tree containing only the entries whose keys satisfy `pred`, which is called once
+    /// per key in order. The survivors are bulk-built into a fresh balanced tree in O(n).
+    pub fn filter_keys<F>(&self, mut pred: F) -> Tree<K, V, M> where F: FnMut(&K) -> bool {
+        Tree::from_sorted_vec(self.iter()
+                                  .filter(|&(key, _)| pred(key))
+                                  .map(|(key, value)| (key.clone(), value.clone()))
+                                  .collect())
+    }
+
+    /// Extracts the entries whose keys fall within `range`, sharing structure with `self`. This is
+    /// another name for `retain_range`, for when the emphasis is on zooming into a region of a
+    /// large tree rather than evicting what lies outside it.
+    pub fn subtree<R>(&self, range: R) -> Tree<K, V, M> where R: RangeBounds<K> {
+        self.retain_range(range)
+    }
+
+    /// Returns a tree in which each value whose key falls within `range` is replaced with
+    /// `f(key, value)`. The keys and shape are unchanged, and subtrees lying wholly outside the
+    /// range are shared with `self`, so this is O(log n + k) for k entries in the range.
+    pub fn map_values_in_range<R, F>(&self, range: R, mut f: F) -> Tree<K, V, M>
+                                     where R: RangeBounds<K>, F: FnMut(&K, &V) -> V {
+        Tree::from_root(self.root.map_values_in_range(&range, &mut f))
+    }
+
+    /// Inserts `key` only if it is not already present.
+    ///
+    /// On a collision, returns `Err((tree, value))`, where `tree` shares its root with `self` and
+    /// `value` is the value that was passed in, handed back so the caller can reuse it.
+    #[allow(clippy::type_complexity)]
+    pub fn insert_unique(&self, key: K, value: V) -> Result<Tree<K, V, M>, (Tree<K, V, M>, V)> {
+        let mut rejected = None;
+        let root = self.root.insert_with(key, |existing| {
+            match existing {
+                None => Some(value),
+                Some(_) => {
+                    rejected = Some(value);
+                    None
+                }
+            }
+        });
+        match root {
+            Some(root) => Ok(Tree::from_root(root.blacken()).verified("insert_unique")),
+            None => Err((self.clone(), rejected.unwrap())),
+        }
+    }
+
+    /// Sets the value at `key` to `on_present` of the current value if there is one, or to
+    /// `on_absent()` otherwise, in a single descent.
+    pub fn upsert<F, G>(&self, key: K, on_present: F, on_absent: G) -> Tree<K, V, M>
+                        where F: FnOnce(&V) -> V, G: FnOnce() -> V {
+        let root = self.root.insert_with(key, |existing| {
+            Some(match existing {
+                Some(existing) => on_present(existing),
+                None => on_absent(),
+            })
+        });
+        Tree::from_root(root.unwrap().blacken()).verified("upsert")
+    }
+
+    /// Inserts `key` with `value` unless that would grow the tree past `cap` entries, in which
+    /// case `value` is handed back. Updating a key that is already present always succeeds,
+    /// whatever the capacity, since it doesn't change the length.
+    pub fn insert_bounded(&self, key: K, value: V, cap: usize) -> Result<Tree<K, V, M>, V> {
+        if self.len() >= cap && self.get(&key).is_none() {
+            return Err(value)
+        }
+        Ok(self.insert(key, value))
+    }
+
+    /// Inserts `key` only if it is not already present, like `insert_unique`, but reports a
+    /// collision in the shape of the standard library's `OccupiedError`.
+    pub fn try_insert(&self, key: K, value: V) -> Result<Tree<K, V, M>, OccupiedError<V>> {
+        let mut error = None;
+        let root = self.root.insert_with(key, |existing| {
+            match existing {
+                None => Some(value),
+                Some(existing) => {
+                    error = Some(OccupiedError { existing: existing.clone(), value: value });
+                    None
+                }
+            }
+        });
+        match root {
+            Some(root) => Ok(Tree::from_root(root.blacken()).verified("try_insert")),
+            None => Err(error.unwrap()),
+        }
+    }
+
+    /// Returns a clone of the value at `key` if there is one, and otherwise inserts the value
+    /// computed by `default`, which is called only in that case. The tree is returned alongside,
+    /// sharing its root with `self` if nothing was inserted.
+    pub fn get_or_insert_with<F>(&self, key: K, default: F) -> (Tree<K, V, M>, V)
+                                 where F: FnOnce() -> V {
+        let mut resolved = None;
+        let root = self.root.insert_with(key, |existing| {
+            match existing {
+                None => {
+                    let value = default();
+                    resolved = Some(value.clone());
+                    Some(value)
+                }
+                Some(existing) => {
+                    resolved = Some(existing.clone());
+                    None
+                }
+            }
+        });
+        let tree = match root {
+            Some(root) => Tree::from_root(root.blacken()).verified("get_or_insert_with"),
+            None => self.clone(),
+        };
+        (tree, resolved.unwrap())
+    }
+
+    /// Estimates the number of heap bytes that would be freed if this tree were dropped: the nodes
+    /// not shared with any other tree, and the reference counts that accompany them.
+    ///
+    /// This is only an estimate. It ignores allocations owned by keys or values themselves, such
+    /// as the buffer of a `String`, and any allocator overhead.
+    pub fn approximate_heap_size(&self) -> usize {
+        self.root.unshared_heap_size()
+    }
+
+    /// Iterates over every element of every value's slice, paired with its key: in key order,
+    /// then in slice order within each value. Useful when the tree is used as a multimap.
+    pub fn iter_flat<'a, T>(&'a self) -> impl Iterator<Item = (&'a K, &'a T)> + 'a
+                            where V: AsRef<[T]>, T: 'a {
+        self.iter().flat_map(|(key, value)| value.as_ref().iter().map(move |item| (key, item)))
+    }
+
+    /// Rebuilds the tree with minimal height from its entries, in O(n).
+    ///
+    /// The result shares no nodes with `self` or any other tree, so holding onto only the
+    /// compacted tree lets the nodes of older versions be freed.
+    pub fn compact(&self) -> Tree<K, V, M> {
+        Tree::from_sorted_vec(self.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+    }
+
+    /// Returns this tree if it satisfies `check_invariants`, and otherwise a balanced rebuild of
+    /// its entries. If the keys are out of order, they are sorted first, and the last of any
+    /// duplicates in the in-order walk wins.
+    ///
+    /// This assumes that `K`'s comparisons are still consistent, so that sorting can repair the
+    /// order; `debug_check_key_order` describes what that means.
+    pub fn repair(&self) -> Tree<K, V, M> {
+        let ordered = self.debug_check_key_order();
+        if ordered && self.root.checked_black_height().is_some() {
+            return self.clone()
+        }
+        let entries = self.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        if ordered {
+            Tree::from_sorted_vec(entries)
+        } else {
+            Tree::from_unsorted_vec(entries)
+        }
+    }
+
+    /// Returns true if the keys are in strictly ascending order when walked in order, and `Ord`
+    /// and `PartialOrd` agree, in both directions, on every adjacent pair. A `K` whose comparisons
+    /// are inconsistent can make entries unreachable, and this is a quick way to detect that.
+    pub fn debug_check_key_order(&self) -> bool {
+        let mut keys = self.iter().map(|(key, _)| key);
+        let first = match keys.next() {
+            None => return true,
+            Some(first) => first,
+        };
+        keys.try_fold(first, |prev, key| {
+            let consistent = prev.cmp(key) == Ordering::Less &&
+                key.cmp(prev) == Ordering::Greater &&
+                prev.partial_cmp(key) == Some(Ordering::Less) &&
+                key.partial_cmp(prev) == Some(Ordering::Greater);
+            if consistent { Some(key) } else { None }
+        }).is_some()
+    }
+
+    /// Lists the entries in level order, from the root down and left to right within each level,
+    /// along with the color of each one's node. This captures the exact shape of the tree, which
+    /// `from_level_order` restores.
+    pub fn to_level_order(&self) -> Vec<(K, V, Color)> {
+        let (mut entries, mut queue) = (Vec::with_capacity(self.len()), VecDeque::new());
+        queue.extend(self.root.to_option());
+        while let Some(node) = queue.pop_front() {
+            entries.push((node.key.clone(), node.value.clone(), node.color));
+            queue.extend(node.left.to_option());
+            queue.extend(node.right.to_option())
+        }
+        entries
+    }
+
+    /// Rebuilds a tree with exactly the shape described by `entries`, as produced by
+    /// `to_level_order`, in O(n) and without rebalancing.
+    ///
+    /// Returns `None` if `entries` does not describe a valid tree; `from_structure_checked`
+    /// explains why.
+    pub fn from_level_order(entries: Vec<(K, V, Color)>) -> Option<Tree<K, V, M>> {
+        Tree::from_structure_checked(entries).ok()
+    }
+
+    /// Rebuilds a tree from its level order like `from_level_order`, but on failure, reports the
+    /// first problem found and the position in `entries` of the node at fault.
+    pub fn from_structure_checked(entries: Vec<(K, V, Color)>)
+                                  -> Result<Tree<K, V, M>, StructureError> {
+        if entries.is_empty() {
+            return Ok(Tree::default())
+        }
+
+        // Find each entry's children. In level order, a node's children come after it and before
+        // those of any later node, so each entry is the child of the earliest node that still has
+        // a free slot whose key bounds admit it. A left slot is no longer free once the right one
+        // is filled, since a left child precedes its sibling. Bounds are indices of the nearest
+        // ancestors the slot lies after and before.
+        let mut children = vec![(None, None); entries.len()];
+        let mut slots = VecDeque::new();
+        slots.push_back((0, None, None));
+        for index in 1..entries.len() {
+            let key = &entries[index].0;
+            loop {
+                let (parent, lower, upper) = match slots.front() {
+                    None => return Err(StructureError::OrderingViolation(index)),
+                    Some(&slot) => slot,
+                };
+                let parent_key = &entries[parent].0;
+                let after_lower = lower.is_none_or(|lower: usize| key > &entries[lower].0);
+                let before_upper = upper.is_none_or(|upper: usize| key < &entries[upper].0);
+                let left_free = children[parent].0.is_none() && children[parent].1.is_none();
+                if left_free && key < parent_key && after_lower {
+                    children[parent].0 = Some(index);
+                    slots.push_back((index, lower, Some(parent)));
+                    break
+                }
+                if children[parent].1.is_none() && key > parent_key && before_upper {
+                    children[parent].1 = Some(index);
+                    slots.push_back((index, Some(parent), upper));
+                    break
+                }
+                slots.pop_front();
+            }
+        }
+
+        // Build the nodes from the last to the first, so that children are built before their
+        // parents, checking the coloring of each as it is built.
+        let mut built: Vec<Option<Subtree<K, V, M>>> = (0..entries.len()).map(|_| None).collect();
+        let mut entries = entries;
+        while let Some((key, value, color)) = entries.pop() {
+            let index = entries.len();
+            let mut take = |child: Option<usize>| {
+                child.map_or((Link::Empty, 0), |child| built[child].take().unwrap())
+            };
+            let ((left, left_height), (right, right_height)) =
+                (take(children[index].0), take(children[index].1));
+            if color.is_double_black() {
+                return Err(StructureError::DoubleBlack(index))
+            }
+            if color.is_red() && (left.get_if_red().is_some() || right.get_if_red().is_some()) {
+                return Err(StructureError::RedRed(index))
+            }
+            if left_height != right_height {
+                return Err(StructureError::UnequalBlackHeight(index))
+            }
+            let height = left_height + color.is_black() as usize;
+            built[index] = Some((Link::node(color, key, value, left, right), height));
+        }
+        Ok(Tree::from_root(built[0].take().unwrap().0))
+    }
+
+    /// Builds a tree from `iter`, reserving room for at least `capacity` entries in the
+    /// intermediate buffer used by the bulk builder. Useful when the iterator's `size_hint` is
+    /// less precise than what the caller knows. As with `collect`, later duplicates win.
+    pub fn with_capacity_hint<I>(iter: I, capacity: usize) -> Tree<K, V, M>
+                                 where I: IntoIterator<Item = (K, V)> {
+        let iter = iter.into_iter();
+        let mut entries = Vec::with_capacity(cmp::max(capacity, iter.size_hint().0));
+        entries.extend(iter);
+        Tree::from_unsorted_vec(entries)
+    }
+
+    /// Builds a tree from `iter` like `collect`, but fails instead of letting a later duplicate
+    /// win. The error holds the key of the earliest entry in `iter` whose key was already seen.
+    pub fn try_from_iter<I>(iter: I) -> Result<Tree<K, V, M>, DuplicateKey<K>>
+                            where I: IntoIterator<Item = (K, V)> {
+        let mut entries: Vec<(usize, (K, V))> = iter.into_iter().enumerate().collect();
+        entries.sort_by(|a, b| (a.1).0.cmp(&(b.1).0));
+        // The sort is stable, so the second of two adjacent equal keys is the repeat.
+        let duplicate = entries.windows(2)
+                               .enumerate()
+                               .filter(|&(_, pair)| (pair[0].1).0 == (pair[1].1).0)
+                               .min_by_key(|&(_, pair)| pair[1].0)
+                               .map(|(position, _)| position + 1);
+        if let Some(position) = duplicate {
+            return Err(DuplicateKey { key: (entries.swap_remove(position).1).0 })
+        }
+        Ok(Tree::from_sorted_vec(entries.into_iter().map(|(_, entry)| entry).collect()))
+    }
+
+    fn from_unsorted_vec(mut entries: Vec<(K, V)>) -> Tree<K, V, M> {
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Tree::from_stably_sorted_vec(entries)
+    }
+
+    // Builds a tree from entries sorted by a stable sort, keeping the last of any duplicates.
+    fn from_stably_sorted_vec(mut entries: Vec<(K, V)>) -> Tree<K, V, M> {
+        // Swapping each duplicate into the retained slot keeps the last value seen for every key.
+        entries.dedup_by(|next, prev| {
+            if next.0 == prev.0 {
+                mem::swap(next, prev);
+                true
+            } else {
+                false
+            }
+        });
+        Tree::from_sorted_vec(entries)
+    }
+
+    // With the `paranoid` feature, panics if this tree, just returned by `operation`, violates the
+    // red-black invariants, so that a rebalancing bug is caught where it happens. Key order is
+    // left to `debug_check_key_order`, since only an inconsistent `Ord` can break it. Without the
+    // feature, this does nothing.
+    #[inline]
+    fn verified(self, operation: &str) -> Tree<K, V, M> {
+        if cfg!(feature = "paranoid") {
+            assert!(self.root.checked_black_height().is_some(),
+                    "`{}` produced a tree that violates the red-black invariants",
+                    operation);
+        }
+        self
+    }
+
+    // Wraps a newly built root, stamping it with a fresh version unless it is empty.
+    fn from_root(root: Link<K, V, M>) -> Tree<K, V, M> {
+        let version = match root {
+            Link::Empty => 0,
+            Link::Node(_) => NEXT_VERSION.fetch_add(1, atomic::Ordering::Relaxed),
+        };
+        Tree {
+            root: root,
+            version: version,
+        }
+    }
+
+    fn from_sorted_vec(entries: Vec<(K, V)>) -> Tree<K, V, M> {
+        let len = entries.len();
+        Tree::from_root(Link::from_sorted(&mut entries.into_iter(), len, 0, red_depth(len)))
+    }
+
+    /// Checks that keys are in strictly ascending order, that no red node has a red child, that
+    /// every path from the root to a leaf has the same number of black nodes, that no
+    /// double-black nodes remain, and that cached subtree sizes are correct.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn check_invariants(&self) -> bool {
+        self.debug_check_key_order() && self.root.checked_black_height().is_some()
+    }
+
+    /// Renders the shape of the tree, one node per line in preorder, each indented by its depth
+    /// and showing its key, color, and depth. Children are marked `L` or `R`.
+    #[cfg(feature = "testing")]
+    pub fn debug_structure(&self) -> String where K: Debug {
+        let mut output = String::new();
+        self.root.write_structure(&mut output, "", 0);
+        output
+    }
+}
+
+// Whether `key` satisfies the lower bound of `range`.
+fn after_start<K, R>(range: &R, key: &K) -> bool where K: Ord, R: RangeBounds<K> {
+    match range.start_bound() {
+        Bound::Included(start) => key >= start,
+        Bound::Excluded(start) => key > start,
+        Bound::Unbounded => true,
+    }
+}
+
+// Whether `key` satisfies the upper bound of `range`.
+fn before_end<K, R>(range: &R, key: &K) -> bool where K: Ord, R: RangeBounds<K> {
+    match range.end_bound() {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
+    }
+}
+
+// Depth of the bottommost, possibly incomplete, level of a tree of `len` nodes built by halving.
+// Coloring that level red and everything above it black satisfies the red-black invariants.
+fn red_depth(len: usize) -> usize {
+    let mut depth = 0;
+    while (2 << depth) <= len + 1 {
+        depth += 1
+    }
+    depth
+}
+
+impl<K, V, M> Link<K, V, M> where K: Clone + PartialOrd + Ord, V: Clone, M: Monoid<K, V> {
+    fn get_by<F>(&self, mut compare: F) -> Option<(&K, &V)>
+                 where F: for<'a> FnMut(&'a K) -> Ordering {
+        match *self {
+            Link::Empty => None,
+            Link::Node(ref node) => {
+                match compare(&node.key) {
+                    Ordering::Less => node.left.get_by(compare),
+                    Ordering::Greater => node.right.get_by(compare),
+                    Ordering::Equal => Some((&node.key, &node.value)),
+                }
+            }
+        }
+    }
+
+    fn from_sorted<I>(entries: &mut I, len: usize, depth: usize, red_depth: usize) -> Link<K, V, M>
+                      where I: Iterator<Item = (K, V)> {
+        if len == 0 {
+            return Link::Empty
+        }
+        let left_len = len / 2;
+        let left = Link::from_sorted(entries, left_len, depth + 1, red_depth);
+        let (key, value) = entries.next().unwrap();
+        let right = Link::from_sorted(entries, len - left_len - 1, depth + 1, red_depth);
+        let color = if depth == red_depth { Color::Red } else { Color::Black };
+        Link::node(color, key, value, left, right)
+    }
+
+    fn insert(&self, key: K, value: V) -> Link<K, V, M> {
+        self.insert_with(key, |_| Some(value)).unwrap()
+    }
+
+    // Inserts `key` with the value returned by `f`, which is passed the existing value if the key
+    // is already present. Returns `None`, having allocated nothing, if `f` does.
+    fn insert_with<F>(&self, key: K, f: F) -> Option<Link<K, V, M>>
+                      where F: FnOnce(Option<&V>) -> Option<V> {
+        match *self {
+            Link::Empty => {
+                Some(Link::node(Color::Red, key, f(None)?, Link::Empty, Link::Empty))
+            }
+            Link::Node(ref node) => {
+                match key.cmp(&node.key) {
+                    Ordering::Less => {
+                        Some(node.color.balance(node.key.clone(),
+                                                node.value.clone(),
+                                                node.left.insert_with(key, f)?,
+                                                node.right.clone()))
+                    }
+                    Ordering::Greater => {
+                        Some(node.color.balance(node.key.clone(),
+                                                node.value.clone(),
+                                                node.left.clone(),
+                                                node.right.insert_with(key, f)?))
+                    }
+                    Ordering::Equal => {
+                        Some(Link::node(node.color,
+                                        key,
+                                        f(Some(&node.value))?,
+                                        node.left.clone(),
+                                        node.right.clone()))
+                    }
+                }
+            }
+        }
+    }
+
+    // Removes `key`, which must be present. Following Germane and Might, "Deletion: The Curse of
+    // the Red-Black Tree", a removed black leaf leaves behind a double-black empty link, which
+    // is indistinguishable from `Link::Empty` here. That's fine, because `rotate` only treats an
+    // empty link as double-black when its sibling shows the black heights to be unequal.
+    fn remove<Q>(&self, key: &Q) -> Link<K, V, M> where K: Borrow<Q>, Q: ?Sized + Ord {
+        let node = match *self {
+            Link::Empty => return Link::Empty,
+            Link::Node(ref node) => node,
+        };
+        let ordering = key.cmp(node.key.borrow());
+        match (node.left.to_option(), node.right.to_option()) {
+            (None, None) => {
+                debug_assert!(ordering == Ordering::Equal);
+                Link::Empty
+            }
+            // A black node whose only child is a red leaf.
+            (Some(left), None) if node.color.is_black() => {
+                match ordering {
+                    Ordering::Less => {
+                        Link::node(Color::Black,
+                                   node.key.clone(),
+                                   node.value.clone(),
+                                   node.left.remove(key),
+                                   Link::Empty)
+                    }
+                    Ordering::Equal => left.recolor(Color::Black),
+                    Ordering::Greater => self.clone(),
+                }
+            }
+            _ => {
+                match ordering {
+                    Ordering::Less => {
+                        node.color.rotate(node.key.clone(),
+                                          node.value.clone(),
+                                          node.left.remove(key),
+                                          node.right.clone())
+                    }
+                    Ordering::Greater => {
+                        node.color.rotate(node.key.clone(),
+                                          node.value.clone(),
+                                          node.left.clone(),
+                                          node.right.remove(key))
+                    }
+                    Ordering::Equal => {
+                        let (key, value, right) = node.right.remove_min();
+                        node.color.rotate(key, value, node.left.clone(), right)
+                    }
+                }
+            }
+        }
+    }
+
+    // Removes the minimum entry of a nonempty subtree, returning it along with the new subtree.
+    fn remove_min(&self) -> (K, V, Link<K, V, M>) {
+        let node = match *self {
+            Link::Empty => panic!("remove_min() called on an empty subtree"),
+            Link::Node(ref node) => node,
+        };
+        match (node.left.to_option(), node.right.to_option()) {
+            (None, None) => (node.key.clone(), node.value.clone(), Link::Empty),
+            (None, Some(right)) => {
+                (node.key.clone(), node.value.clone(), right.recolor(Color::Black))
+            }
+            _ => {
+                let (key, value, left) = node.left.remove_min();
+                (key, value, node.color.rotate(node.key.clone(), node.value.clone(), left,
+                                               node.right.clone()))
+            }
+        }
+    }
+
+    // Paints a red root with a red child black, which finishes an insertion.
+    fn blacken(self) -> Link<K, V, M> {
+        match self {
+            Link::Node(ref node) if node.color.is_red() &&
+                    (node.left.get_if_red().is_some() || node.right.get_if_red().is_some()) => {
+                node.recolor(Color::Black)
+            }
+            link => link,
+        }
+    }
+
+    // Paints a black root with two black children red before a removal, so that removing from
+    // either side can borrow from it.
+    fn redden(&self) -> Link<K, V, M> {
+        match *self {
+            Link::Node(ref node) if node.color.is_black() &&
+                    node.left.get_if_black().is_some() && node.right.get_if_black().is_some() => {
+                node.recolor(Color::Red)
+            }
+            _ => self.clone(),
+        }
+    }
+
+    // Rebuilds the path to `key` with the value replaced by `f(&old)`. Returns `None`, having
+    // allocated nothing, if the key is absent or `f` declines to replace the value.
+    fn modify<Q, F>(&self, key: &Q, f: F) -> Option<Link<K, V, M>>
+                    where K: Borrow<Q>, Q: ?Sized + Ord, F: FnOnce(&V) -> Option<V> {
+        let node = match *self {
+            Link::Empty => return None,
+            Link::Node(ref node) => node,
+        };
+        let (value, left, right) = match key.cmp(node.key.borrow()) {
+            Ordering::Less => {
+                (node.value.clone(), node.left.modify(key, f)?, node.right.clone())
+            }
+            Ordering::Greater => {
+                (node.value.clone(), node.left.clone(), node.right.modify(key, f)?)
+            }
+            Ordering::Equal => (f(&node.value)?, node.left.clone(), node.right.clone()),
+        };
+        Some(Link::node(node.color, node.key.clone(), value, left, right))
+    }
+
+    fn count_where<F>(&self, pred: &mut F) -> usize where F: FnMut(&K, &V) -> bool {
+        match *self {
+            Link::Empty => 0,
+            Link::Node(ref node) => {
+                node.left.count_where(pred) + pred(&node.key, &node.value) as usize +
+                    node.right.count_where(pred)
+            }
+        }
+    }
+
+    fn intern_values<'a>(&'a self, interned: &mut HashMap<&'a V, Arc<V>>) -> Link<K, Arc<V>, ()>
+                         where V: Eq + Hash {
+        let node = match *self {
+            Link::Empty => return Link::Empty,
+            Link::Node(ref node) => node,
+        };
+        let left = node.left.intern_values(interned);
+        let value = interned.entry(&node.value).or_insert_with(|| Arc::new(node.value.clone()));
+        let value = value.clone();
+        let right = node.right.intern_values(interned);
+        Link::node(node.color, node.key.clone(), value, left, right)
+    }
+
+    fn count_colors(&self, counts: &mut (usize, usize, usize)) {
+        if let Link::Node(ref node) = *self {
+            match node.color {
+                Color::Red => counts.0 += 1,
+                Color::Black => counts.1 += 1,
+                Color::DoubleBlack => counts.2 += 1,
+            }
+            node.left.count_colors(counts);
+            node.right.count_colors(counts)
+        }
+    }
+
+    // Finds the node with the smallest key greater than `key`, or equal to it if `inclusive`.
+    fn first_above<Q>(&self, key: &Q, inclusive: bool) -> Option<&Arc<Node<K, V, M>>>
+                      where K: Borrow<Q>, Q: ?Sized + Ord {
+        let (mut link, mut candidate) = (self, None);
+        while let Link::Node(ref node) = *link {
+            match key.cmp(node.key.borrow()) {
+                Ordering::Less => {
+                    candidate = Some(node);
+                    link = &node.left
+                }
+                Ordering::Equal if inclusive => return Some(node),
+                Ordering::Equal | Ordering::Greater => link = &node.right,
+            }
+        }
+        candidate
+    }
+
+    // Finds the node with the largest key less than `key`, or equal to it if `inclusive`.
+    fn last_below<Q>(&self, key: &Q, inclusive: bool) -> Option<&Arc<Node<K, V, M>>>
+                     where K: Borrow<Q>, Q: ?Sized + Ord {
+        let (mut link, mut candidate) = (self, None);
+        while let Link::Node(ref node) = *link {
+            match key.cmp(node.key.borrow()) {
+                Ordering::Greater => {
+                    candidate = Some(node);
+                    link = &node.right
+                }
+                Ordering::Equal if inclusive => return Some(node),
+                Ordering::Equal | Ordering::Less => link = &node.left,
+            }
+        }
+        candidate
+    }
+
+    // Calls `f` on each entry in key order until it returns false, and returns whether it never
+    // did.
+    fn visit_while<F>(&self, f: &mut F) -> bool where F: FnMut(&K, &V) -> bool {
+        match *self {
+            Link::Empty => true,
+            Link::Node(ref node) => {
+                node.left.visit_while(f) && f(&node.key, &node.value) && node.right.visit_while(f)
+            }
+        }
+    }
+
+    // Calls `f` on each entry within `range` in key order, skipping subtrees entirely outside it.
+    fn visit_range<R, F>(&self, range: &R, f: &mut F) where R: RangeBounds<K>, F: FnMut(&K, &V) {
+        if let Link::Node(ref node) = *self {
+            let (after_start, before_end) = (after_start(range, &node.key),
+                                             before_end(range, &node.key));
+            if after_start {
+                node.left.visit_range(range, f)
+            }
+            if after_start && before_end {
+                f(&node.key, &node.value)
+            }
+            if before_end {
+                node.right.visit_range(range, f)
+            }
+        }
+    }
+
+    fn map_values_in_range<R, F>(&self, range: &R, f: &mut F) -> Link<K, V, M>
+                                 where R: RangeBounds<K>, F: FnMut(&K, &V) -> V {
+        let node = match *self {
+            Link::Empty => return Link::Empty,
+            Link::Node(ref node) => node,
+        };
+        let (after_start, before_end) = (after_start(range, &node.key),
+                                         before_end(range, &node.key));
+        let left = if after_start {
+            node.left.map_values_in_range(range, f)
+        } else {
+            node.left.clone()
+        };
+        let value = if after_start && before_end {
+            f(&node.key, &node.value)
+        } else {
+            node.value.clone()
+        };
+        let right = if before_end {
+            node.right.map_values_in_range(range, f)
+        } else {
+            node.right.clone()
+        };
+        Link::node(node.color, node.key.clone(), value, left, right)
+    }
+
+    // Everything below a shared node is reachable from elsewhere, so only the unshared nodes at
+    // the top of the tree are counted.
+    fn unshared_heap_size(&self) -> usize {
+        match *self {
+            Link::Node(ref node) if Arc::strong_count(node) == 1 => {
+                mem::size_of::<Node<K, V, M>>() + 2 * mem::size_of::<usize>() +
+                    node.left.unshared_heap_size() + node.right.unshared_heap_size()
+            }
+            Link::Node(_) | Link::Empty => 0,
+        }
+    }
+
+    // Returns the number of black nodes on every path to a leaf, or `None` if paths disagree,
+    // the subtree contains a red node with a red child or a double-black node, or a node's size
+    // is wrong.
+    fn checked_black_height(&self) -> Option<usize> {
+        match *self {
+            Link::Empty => Some(0),
+            Link::Node(ref node) => {
+                if node.color.is_double_black() ||
+                        (node.color.is_red() && (node.left.get_if_red().is_some() ||
+                                                 node.right.get_if_red().is_some())) {
+                    return None
+                }
+                let left = node.left.checked_black_height()?;
+                if node.right.checked_black_height()? != left ||
+                        node.size != node.left.len() + node.right.len() + 1 {
+                    return None
+                }
+                Some(left + node.color.is_black() as usize)
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn height(&self) -> usize {
+        match *self {
+            Link::Empty => 0,
+            Link::Node(ref node) => 1 + cmp::max(node.left.height(), node.right.height()),
+        }
+    }
+
+    // Appends a line for each node of this subtree to `output`, as `Tree::debug_structure` does.
+    #[cfg(feature = "testing")]
+    fn write_structure(&self, output: &mut String, side: &str, depth: usize) where K: Debug {
+        if let Link::Node(ref node) = *self {
+            let indent = "  ".repeat(depth);
+            output.push_str(&format!("{}{}{:?} {:?} depth {}\n",
+                                     indent, side, node.key, node.color, depth));
+            node.left.write_structure(output, "L ", depth + 1);
+            node.right.write_structure(output, "R ", depth + 1)
+        }
+    }
+
+    fn select(&self, index: usize) -> Option<&Arc<Node<K, V, M>>> {
+        let (mut link, mut index) = (self, index);
+        while let Link::Node(ref node) = *link {
+            let left_len = node.left.len();
+            match index.cmp(&left_len) {
+                Ordering::Less => link = &node.left,
+                Ordering::Equal => return Some(node),
+                Ordering::Greater => {
+                    index -= left_len + 1;
+                    link = &node.right
+                }
+            }
+        }
+        None
+    }
+
+    // Counts the keys for which `pred` holds, given that it holds for every key before the first
+    // for which it fails.
+    fn count_prefix<F>(&self, pred: &F) -> usize where F: Fn(&K) -> bool {
+        let (mut link, mut count) = (self, 0);
+        while let Link::Node(ref node) = *link {
+            if pred(&node.key) {
+                count += node.left.len() + 1;
+                link = &node.right
+            } else {
+                link = &node.left
+            }
+        }
+        count
+    }
+
+    // Returns the number of keys less than `key`, and whether `key` itself is present.
+    fn rank<Q>(&self, key: &Q) -> (usize, bool) where K: Borrow<Q>, Q: ?Sized + Ord {
+        let (mut link, mut rank) = (self, 0);
+        while let Link::Node(ref node) = *link {
+            match key.cmp(node.key.borrow()) {
+                Ordering::Less => link = &node.left,
+                Ordering::Equal => return (rank + node.left.len(), true),
+                Ordering::Greater => {
+                    rank += node.left.len() + 1;
+                    link = &node.right
+                }
+            }
+        }
+        (rank, false)
+    }
+
+    // Replaces the value at in-order position `index`, which must be in bounds.
+    fn set_at(&self, index: usize, value: V) -> Link<K, V, M> {
+        let node = match *self {
+            Link::Empty => unreachable!(),
+            Link::Node(ref node) => node,
+        };
+        let left_len = node.left.len();
+        let (value, left, right) = match index.cmp(&left_len) {
+            Ordering::Less => {
+                (node.value.clone(), node.left.set_at(index, value), node.right.clone())
+            }
+            Ordering::Equal => (value, node.left.clone(), node.right.clone()),
+            Ordering::Greater => {
+                let index = index - left_len - 1;
+                (node.value.clone(), node.left.clone(), node.right.set_at(index, value))
+            }
+        };
+        Link::node(node.color, node.key.clone(), value, left, right)
+    }
+
+    // Splits this subtree, whose black height is `height`, into its first `index` entries and the
+    // rest. Each half is returned with its black height.
+    fn split_at(&self, height: usize, index: usize) -> (Subtree<K, V, M>, Subtree<K, V, M>) {
+        let node = match *self {
+            Link::Empty => return ((Link::Empty, 0), (Link::Empty, 0)),
+            Link::Node(ref node) => node,
+        };
+        let child_height = height - node.color.is_black() as usize;
+        let left_len = node.left.len();
+        if index <= left_len {
+            let (before, (after, after_height)) = node.left.split_at(child_height, index);
+            (before, Link::join(after, after_height,
+                                node.key.clone(), node.value.clone(),
+                                node.right.clone(), child_height))
+        } else {
+            let ((before, before_height), after) =
+                node.right.split_at(child_height, index - left_len - 1);
+            (Link::join(node.left.clone(), child_height,
+                        node.key.clone(), node.value.clone(),
+                        before, before_height), after)
+        }
+    }
+
+    // Splits this subtree, whose black height is `height`, into the entries whose keys satisfy
+    // `before` and the rest. The keys satisfying `before` must come first. Each half is returned
+    // with its black height.
+    fn split_where<F>(&self, height: usize, before: &F) -> (Subtree<K, V, M>, Subtree<K, V, M>)
+                      where F: Fn(&K) -> bool {
+        let node = match *self {
+            Link::Empty => return ((Link::Empty, 0), (Link::Empty, 0)),
+            Link::Node(ref node) => node,
+        };
+        let child_height = height - node.color.is_black() as usize;
+        if !before(&node.key) {
+            let (before, (after, after_height)) = node.left.split_where(child_height, before);
+            (before, Link::join(after, after_height,
+                                node.key.clone(), node.value.clone(),
+                                node.right.clone(), child_height))
+        } else {
+            let ((before, before_height), after) = node.right.split_where(child_height, before);
+            (Link::join(node.left.clone(), child_height,
+                        node.key.clone(), node.value.clone(),
+                        before, before_height), after)
+        }
+    }
+
+    // Joins `left`, the entry `key`/`value`, and `right` into one tree, given the black heights of
+    // `left` and `right`. Every key in `left` must be less than `key`, and every key in `right`
+    // greater. Returns the joined tree and its black height. This is the red-black `join` of
+    // Blelloch, Ferizovic, and Sun, "Just Join for Parallel Ordered Sets", and takes
+    // O(|left_height - right_height| + 1) time.
+    fn join(left: Link<K, V, M>,
+            left_height: usize,
+            key: K,
+            value: V,
+            right: Link<K, V, M>,
+            right_height: usize)
+            -> Subtree<K, V, M> {
+        match left_height.cmp(&right_height) {
+            Ordering::Greater => {
+                let joined = left.join_right(left_height, key, value, right, right_height);
+                joined.blacken_root(left_height)
+            }
+            Ordering::Less => {
+                let joined = right.join_left(right_height, left, left_height, key, value);
+                joined.blacken_root(right_height)
+            }
+            Ordering::Equal if left.get_if_red().is_none() && right.get_if_red().is_none() => {
+                (Link::node(Color::Red, key, value, left, right), left_height)
+            }
+            Ordering::Equal => (Link::node(Color::Black, key, value, left, right), left_height + 1),
+        }
+    }
+
+    // Descends the right spine of `self` to a black subtree of height `right_height` and puts
+    // `key`/`value` and `right` there, rebalancing on the way back up. The root of the result may
+    // be red with a red right child, which `join` then fixes.
+    fn join_right(&self, height: usize, key: K, value: V, right: Link<K, V, M>, right_height: usize)
+                  -> Link<K, V, M> {
+        let node = match *self {
+            Link::Node(ref node) if node.color.is_red() || height > right_height => node,
+            _ => return Link::node(Color::Red, key, value, self.clone(), right),
+        };
+        let child_height = height - node.color.is_black() as usize;
+        let new_right = node.right.join_right(child_height, key, value, right, right_height);
+        if node.color.is_black() {
+            if let Some(new_right) = new_right.get_if_red() {
+                if let Some(new_right_right) = new_right.right.get_if_red() {
+                    return Link::node(Color::Red,
+                                      new_right.key.clone(),
+                                      new_right.value.clone(),
+                                      Link::node(Color::Black,
+                                                 node.key.clone(),
+                                                 node.value.clone(),
+                                                 node.left.clone(),
+                                                 new_right.left.clone()),
+                                      new_right_right.recolor(Color::Black))
+                }
+            }
+        }
+        Link::node(node.color, node.key.clone(), node.value.clone(), node.left.clone(), new_right)
+    }
+
+    // The mirror image of `join_right`.
+    fn join_left(&self, height: usize, left: Link<K, V, M>, left_height: usize, key: K, value: V)
+                 -> Link<K, V, M> {
+        let node = match *self {
+            Link::Node(ref node) if node.color.is_red() || height > left_height => node,
+            _ => return Link::node(Color::Red, key, value, left, self.clone()),
+        };
+        let child_height = height - node.color.is_black() as usize;
+        let new_left = node.left.join_left(child_height, left, left_height, key, value);
+        if node.color.is_black() {
+            if let Some(new_left) = new_left.get_if_red() {
+                if let Some(new_left_left) = new_left.left.get_if_red() {
+                    return Link::node(Color::Red,
+                                      new_left.key.clone(),
+                                      new_left.value.clone(),
+                                      new_left_left.recolor(Color::Black),
+                                      Link::node(Color::Black,
+                                                 node.key.clone(),
+                                                 node.value.clone(),
+                                                 new_left.right.clone(),
+                                                 node.right.clone()))
+                }
+            }
+        }
+        Link::node(node.color, node.key.clone(), node.value.clone(), new_left, node.right.clone())
+    }
+
+    // Finishes a `join` by painting a red root with a red child black, returning the resulting
+    // black height.
+    fn blacken_root(self, height: usize) -> Subtree<K, V, M> {
+        let was_red = self.get_if_red().is_some();
+        let link = self.blacken();
+        let height = if was_red && link.get_if_red().is_none() { height + 1 } else { height };
+        (link, height)
+    }
 
-#[derive(Clone, Debug)]
-struct Node<K, V> {
-    key: K,
-    value: V,
-    left: Link<K, V>,
-    right: Link<K, V>,
-    color: Color,
-}
+    // The number of black nodes on the path to the leftmost leaf, which in a valid tree is the
+    // number on every path.
+    fn black_height(&self) -> usize {
+        let (mut link, mut height) = (self, 0);
+        while let Link::Node(ref node) = *link {
+            height += node.color.is_black() as usize;
+            link = &node.left
+        }
+        height
+    }
 
-#[derive(Clone, Copy, PartialEq, Debug)]
-enum Color {
-    Red,
-    Black,
-    DoubleBlack,
-}
+    // Records in `largest` the biggest node that lies both in this subtree, whose keys are
+    // strictly within `bounds`, and in `other`, whose keys are strictly within `other_bounds`,
+    // unless `largest` is already at least as big. A shared node is no bigger than either root, so
+    // the bigger root can't be it unless the two are the same node; that side is split, and pairs
+    // whose key ranges are disjoint are skipped.
+    fn find_largest_shared<'a, 'b>(&'a self,
+                                   bounds: (Option<&'a K>, Option<&'a K>),
+                                   other: &'b Link<K, V, M>,
+                                   other_bounds: (Option<&'b K>, Option<&'b K>),
+                                   largest: &mut Option<&'a Arc<Node<K, V, M>>>) {
+        let (node, other_node) = match (self.to_option(), other.to_option()) {
+            (Some(node), Some(other_node)) => (node, other_node),
+            _ => return,
+        };
+        let below = |upper: Option<&K>, lower: Option<&K>| {
+            match (upper, lower) {
+                (Some(upper), Some(lower)) => upper <= lower,
+                _ => false,
+            }
+        };
+        let smaller = cmp::min(node.size, other_node.size);
+        if largest.is_some_and(|largest| smaller <= largest.size) ||
+                below(bounds.1, other_bounds.0) || below(other_bounds.1, bounds.0) {
+            return
+        }
+        if Arc::ptr_eq(node, other_node) {
+            *largest = Some(node);
+            return
+        }
+        if node.size >= other_node.size {
+            let (left, right) = ((bounds.0, Some(&node.key)), (Some(&node.key), bounds.1));
+            node.left.find_largest_shared(left, other, other_bounds, largest);
+            node.right.find_largest_shared(right, other, other_bounds, largest)
+        } else {
+            let left = (other_bounds.0, Some(&other_node.key));
+            let right = (Some(&other_node.key), other_bounds.1);
+            self.find_largest_shared(bounds, &other_node.left, left, largest);
+            self.find_largest_shared(bounds, &other_node.right, right, largest)
+        }
+    }
 
-#[derive(Clone, Debug)]
-enum Link<K, V> {
-    Empty,
-    Node(Arc<Node<K, V>>),
-}
+    fn ptr_eq(&self, other: &Link<K, V, M>) -> bool {
+        match (self.to_option(), other.to_option()) {
+            (None, None) => true,
+            (Some(node), Some(other_node)) => Arc::ptr_eq(node, other_node),
+            _ => false,
+        }
+    }
 
-impl<K, V> Tree<K, V> where K: Clone + PartialOrd + Ord, V: Clone {
-    pub fn new() -> Tree<K, V> {
-        Tree {
-            root: Link::Empty,
+    fn is_empty_or_double_black(&self) -> bool {
+        match *self {
+            Link::Empty => true,
+            Link::Node(ref node) => node.color.is_double_black(),
         }
     }
 
-    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V> where K: Borrow<Q>, Q: Ord {
-        self.get_by(|node_key| key.cmp(node_key.borrow())).map(|(_, v)| v)
+    fn iter<'a>(&'a self) -> Iter<'a, K, V, M> {
+        Iter {
+            start: self.to_option(),
+            stack: vec![],
+        }
     }
 
-    pub fn get_by<F>(&self, compare: F) -> Option<(&K, &V)>
-                     where F: for<'a> FnMut(&'a K) -> Ordering {
-        self.root.get_by(compare)
+    fn rev_iter<'a>(&'a self) -> RevIter<'a, K, V, M> {
+        RevIter {
+            start: self.to_option(),
+            stack: vec![],
+        }
     }
 
-    pub fn insert(&self, key: K, value: V) -> Tree<K, V> {
-        Tree {
-            root: self.root.insert(key, value),
+    // Returns an iterator over the entries with keys greater than or equal to `key`.
+    fn iter_from<'a, Q>(&'a self, key: &Q) -> Iter<'a, K, V, M>
+                        where K: Borrow<Q>, Q: ?Sized + Ord {
+        let (mut link, mut stack) = (self, vec![]);
+        while let Link::Node(ref node) = *link {
+            if key <= node.key.borrow() {
+                stack.push(node);
+                link = &node.left
+            } else {
+                link = &node.right
+            }
+        }
+        Iter {
+            start: None,
+            stack: stack,
         }
     }
 
-    pub fn iter<'a>(&'a self) -> Iter<'a, K, V> {
+    // Returns an iterator over the entries from in-order position `index` onward.
+    fn iter_from_index<'a>(&'a self, index: usize) -> Iter<'a, K, V, M> {
+        let (mut link, mut index, mut stack) = (self, index, vec![]);
+        while let Link::Node(ref node) = *link {
+            let left_len = node.left.len();
+            if index <= left_len {
+                stack.push(node);
+                link = &node.left
+            } else {
+                index -= left_len + 1;
+                link = &node.right
+            }
+        }
         Iter {
-            start: self.root.to_option(),
-            stack: vec![],
+            start: None,
+            stack: stack,
         }
     }
-}
 
-impl<K, V> Link<K, V> where K: Clone + PartialOrd + Ord, V: Clone {
-    fn get_by<F>(&self, mut compare: F) -> Option<(&K, &V)>
-                 where F: for<'a> FnMut(&'a K) -> Ordering {
+    // Summarizes the entries within `range`, using cached summaries for subtrees that lie
+    // entirely inside it.
+    fn aggregate_range<R>(&self, range: &R) -> M::Summary where R: RangeBounds<K> {
         match *self {
-            Link::Empty => None,
+            Link::Empty => M::identity(),
+            Link::Node(ref node) if !after_start(range, &node.key) => {
+                node.right.aggregate_range(range)
+            }
+            Link::Node(ref node) if !before_end(range, &node.key) => {
+                node.left.aggregate_range(range)
+            }
             Link::Node(ref node) => {
-                match compare(&node.key) {
-                    Ordering::Less => node.left.get_by(compare),
-                    Ordering::Greater => node.right.get_by(compare),
-                    Ordering::Equal => Some((&node.key, &node.value)),
-                }
+                let left = M::combine(&node.left.aggregate_after_start(range),
+                                      &M::lift(&node.key, &node.value));
+                M::combine(&left, &node.right.aggregate_before_end(range))
             }
         }
     }
 
-    fn insert(&self, key: K, value: V) -> Link<K, V> {
+    // Summarizes the entries satisfying the lower bound of `range`.
+    fn aggregate_after_start<R>(&self, range: &R) -> M::Summary where R: RangeBounds<K> {
         match *self {
-            Link::Empty => {
-                Link::Node(Arc::new(Node {
-                    key: key,
-                    value: value,
-                    left: Link::Empty,
-                    right: Link::Empty,
-                    color: Color::Red,
-                }))
-            }
-            Link::Node(ref node) => {
-                match key.cmp(&node.key) {
-                    Ordering::Less => {
-                        node.color.balance(node.key.clone(),
-                                           node.value.clone(),
-                                           node.left.insert(key, value),
-                                           node.right.clone())
-                    }
-                    Ordering::Greater => {
-                        node.color.balance(node.key.clone(),
-                                           node.value.clone(),
-                                           node.left.clone(),
-                                           node.right.insert(key, value))
-                    }
-                    Ordering::Equal => {
-                        Link::Node(Arc::new(Node {
-                            key: key,
-                            value: value,
-                            left: node.left.clone(),
-                            right: node.right.clone(),
-                            color: node.color,
-                        }))
-                    }
-                }
+            Link::Empty => M::identity(),
+            Link::Node(ref node) if after_start(range, &node.key) => {
+                let left = M::combine(&node.left.aggregate_after_start(range),
+                                      &M::lift(&node.key, &node.value));
+                M::combine(&left, &node.right.summary())
             }
+            Link::Node(ref node) => node.right.aggregate_after_start(range),
         }
     }
 
-    fn is_empty_or_double_black(&self) -> bool {
+    // Summarizes the entries satisfying the upper bound of `range`.
+    fn aggregate_before_end<R>(&self, range: &R) -> M::Summary where R: RangeBounds<K> {
         match *self {
-            Link::Empty => true,
-            Link::Node(ref node) => node.color.is_double_black(),
+            Link::Empty => M::identity(),
+            Link::Node(ref node) if before_end(range, &node.key) => {
+                let left = M::combine(&node.left.summary(), &M::lift(&node.key, &node.value));
+                M::combine(&left, &node.right.aggregate_before_end(range))
+            }
+            Link::Node(ref node) => node.left.aggregate_before_end(range),
         }
     }
 
-    fn to_option(&self) -> Option<&Arc<Node<K, V>>> {
+    fn to_option(&self) -> Option<&Arc<Node<K, V, M>>> {
         match *self {
             Link::Empty => None,
             Link::Node(ref node) => Some(node),
         }
     }
 
-    fn get_if_red(&self) -> Option<&Arc<Node<K, V>>> {
+    fn get_if_red(&self) -> Option<&Arc<Node<K, V, M>>> {
         match *self {
             Link::Node(ref node) if node.color.is_red() => Some(node),
             Link::Node(_) | Link::Empty => None,
         }
     }
 
-    fn get_if_black(&self) -> Option<&Arc<Node<K, V>>> {
+    fn get_if_black(&self) -> Option<&Arc<Node<K, V, M>>> {
         match *self {
             Link::Node(ref node) if node.color.is_black() => Some(node),
             Link::Node(_) | Link::Empty => None,
         }
     }
 
-    fn double_black_to_black(&self) -> Link<Arc<Node<K, V>>> {
+    fn double_black_to_black(&self) -> Link<K, V, M> {
         match *self {
             Link::Node(ref node) => {
                 debug_assert!(node.color.is_double_black());
-                Link::Node(Arc::new(Node {
-                    key: self.key.clone(),
-                    value: self.value.clone(),
-                    left: self.left.clone(),
-                    right: self.right.clone(),
-                    color: Color::Black,
-                }))
+                Link::node(Color::Black,
+                           node.key.clone(),
+                           node.value.clone(),
+                           node.left.clone(),
+                           node.right.clone())
             }
             Link::Empty => Link::Empty,
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn rearrangement(color: Color,
                      parent_key: K,
                      parent_value: V,
                      left_key: K,
                      left_value: V,
-                     left_left: Link<K, V>,
-                     left_right: Link<K, V>,
+                     left_left: Link<K, V, M>,
+                     left_right: Link<K, V, M>,
                      right_key: K,
                      right_value: V,
-                     right_left: Link<K, V>,
-                     right_right: Link<K, V>)
-                     -> Link<K, V> {
-        Link::Node(Arc::new(Node {
-            key: parent_key,
-            value: parent_value,
-            left: Link::Node(Arc::new(Node {
-                key: left_key,
-                value: left_value,
-                left: left_left,
-                right: left_right,
-                color: Color::Black,
-            })),
-            right: Link::Node(Arc::new(Node {
-                key: right_key,
-                value: right_value,
-                left: right_left,
-                right: right_right,
-                color: Color::Black,
-            })),
-            color: color,
-        }))
+                     right_left: Link<K, V, M>,
+                     right_right: Link<K, V, M>)
+                     -> Link<K, V, M> {
+        Link::node(color,
+                   parent_key,
+                   parent_value,
+                   Link::node(Color::Black, left_key, left_value, left_left, left_right),
+                   Link::node(Color::Black, right_key, right_value, right_left, right_right))
     }
 }
 
 impl Color {
-    fn balance<K, V>(self, key: K, value: V, left: Link<K, V>, right: Link<K, V>) -> Link<K, V>
-                     where K: Clone + PartialOrd + Ord, V: Clone {
+    fn balance<K, V, M>(self, key: K, value: V, left: Link<K, V, M>, right: Link<K, V, M>)
+                        -> Link<K, V, M>
+                        where K: Clone + PartialOrd + Ord, V: Clone, M: Monoid<K, V> {
         if self.is_black() {
-            if let Some(ref left) = left.get_if_red() {
-                if let Some(ref left_left) = left.left.get_if_red() {
+            if let Some(left) = left.get_if_red() {
+                if let Some(left_left) = left.left.get_if_red() {
                     return Link::rearrangement(Color::Red,
                                                left.key.clone(),
                                                left.value.clone(),
@@ -220,7 +2525,7 @@ impl Color {
                                                left.right.clone(),
                                                right)
                 }
-                if let Some(ref left_right) = left.right.get_if_red() {
+                if let Some(left_right) = left.right.get_if_red() {
                     return Link::rearrangement(Color::Red,
                                                left_right.key.clone(),
                                                left_right.value.clone(),
@@ -234,8 +2539,8 @@ impl Color {
                                                right)
                 }
             }
-            if let Some(ref right) = right.get_if_red() {
-                if let Some(ref right_left) = right.left.get_if_red() {
+            if let Some(right) = right.get_if_red() {
+                if let Some(right_left) = right.left.get_if_red() {
                     return Link::rearrangement(Color::Red,
                                                right_left.key.clone(),
                                                right_left.value.clone(),
@@ -248,7 +2553,7 @@ impl Color {
                                                right_left.right.clone(),
                                                right.right.clone())
                 }
-                if let Some(ref right_right) = right.right.get_if_red() {
+                if let Some(right_right) = right.right.get_if_red() {
                     return Link::rearrangement(Color::Red,
                                                right.key.clone(),
                                                right.value.clone(),
@@ -265,8 +2570,8 @@ impl Color {
         }
 
         if self.is_double_black() {
-            if let Some(ref left) = left.get_if_red() {
-                if let Some(ref left_right) = left.right.get_if_red() {
+            if let Some(left) = left.get_if_red() {
+                if let Some(left_right) = left.right.get_if_red() {
                     return Link::rearrangement(Color::Black,
                                                left_right.key.clone(),
                                                left_right.value.clone(),
@@ -280,8 +2585,8 @@ impl Color {
                                                right)
                 }
             }
-            if let Some(ref right) = right.get_if_red() {
-                if let Some(ref right_left) = right.left.get_if_red() {
+            if let Some(right) = right.get_if_red() {
+                if let Some(right_left) = right.left.get_if_red() {
                     return Link::rearrangement(Color::Black,
                                                right_left.key.clone(),
                                                right_left.value.clone(),
@@ -297,29 +2602,22 @@ impl Color {
             }
         }
 
-        Link::Node(Arc::new(Node {
-            key: key,
-            value: value,
-            left: left,
-            right: right,
-            color: self,
-        }))
+        Link::node(self, key, value, left, right)
     }
 
-    fn rotate<K, V>(self, key: K, value: V, left: Link<K, V>, right: Link<K, V>) -> Link<K, V>
-                    where K: Clone + PartialOrd + Ord, V: Clone {
+    fn rotate<K, V, M>(self, key: K, value: V, left: Link<K, V, M>, right: Link<K, V, M>)
+                       -> Link<K, V, M>
+                       where K: Clone + PartialOrd + Ord, V: Clone, M: Monoid<K, V> {
         if self.is_red() {
             if left.is_empty_or_double_black() {
                 if let Some(right) = right.get_if_black() {
                     return Color::Black.balance(right.key.clone(),
                                                 right.value.clone(),
-                                                Link::Node(Arc::new(Node {
-                                                    color: Color::Red,
-                                                    key: key,
-                                                    value: value,
-                                                    left: left.double_black_to_black(),
-                                                    right: right.left.clone(),
-                                                })),
+                                                Link::node(Color::Red,
+                                                           key,
+                                                           value,
+                                                           left.double_black_to_black(),
+                                                           right.left.clone()),
                                                 right.right.clone())
                 }
             }
@@ -328,13 +2626,11 @@ impl Color {
                     return Color::Black.balance(left.key.clone(),
                                                 left.value.clone(),
                                                 left.left.clone(),
-                                                Link::Node(Arc::new(Node {
-                                                    color: Color::Red,
-                                                    key: key,
-                                                    value: value,
-                                                    left: left.right.clone(),
-                                                    right: right.double_black_to_black(),
-                                                })))
+                                                Link::node(Color::Red,
+                                                           key,
+                                                           value,
+                                                           left.right.clone(),
+                                                           right.double_black_to_black()))
                 }
             }
         } else if self.is_black() {
@@ -343,34 +2639,29 @@ impl Color {
                 if let Some(right) = right.get_if_black() {
                     return Color::DoubleBlack.balance(right.key.clone(),
                                                       right.value.clone(),
-                                                      Link::Node(Arc::new(Node {
-                                                          color: Color::Red,
-                                                          key: key,
-                                                          value: value,
-                                                          left: left.double_black_to_black(),
-                                                          right: right.left.clone(),
-                                                      })),
+                                                      Link::node(Color::Red,
+                                                                 key,
+                                                                 value,
+                                                                 left.double_black_to_black(),
+                                                                 right.left.clone()),
                                                       right.right.clone())
                 }
                 // Third case, Figure 9
                 if let Some(right) = right.get_if_red() {
                     if let Some(right_left) = right.left.get_if_black() {
-                        return Link::Node(Arc::new(Node {
-                            color: Color::Black,
-                            key: right.key.clone(),
-                            value: right.value.clone(),
-                            left: Color::Black.balance(right_left.key.clone(),
-                                                       right_left.value.clone(),
-                                                       Link::Node(Arc::new(Node {
-                                                           color: Color::Red,
-                                                           key: key,
-                                                           value: value,
-                                                           left: left.double_black_to_black(),
-                                                           right: right_left.left.clone(),
-                                                       })),
-                                                       right_left.right.clone()),
-                            right: right.right.clone(),
-                        }))
+                        let left = Link::node(Color::Red,
+                                              key,
+                                              value,
+                                              left.double_black_to_black(),
+                                              right_left.left.clone());
+                        return Link::node(Color::Black,
+                                          right.key.clone(),
+                                          right.value.clone(),
+                                          Color::Black.balance(right_left.key.clone(),
+                                                               right_left.value.clone(),
+                                                               left,
+                                                               right_left.right.clone()),
+                                          right.right.clone())
                     }
                 }
             }
@@ -380,45 +2671,34 @@ impl Color {
                     return Color::DoubleBlack.balance(left.key.clone(),
                                                       left.value.clone(),
                                                       left.left.clone(),
-                                                      Link::Node(Arc::new(Node {
-                                                          color: Color::Red,
-                                                          key: key,
-                                                          value: value,
-                                                          left: left.right.clone(),
-                                                          right: right.double_black_to_black(),
-                                                      })))
+                                                      Link::node(Color::Red,
+                                                                 key,
+                                                                 value,
+                                                                 left.right.clone(),
+                                                                 right.double_black_to_black()))
                 }
                 // Third case, Figure 9
                 if let Some(left) = left.get_if_red() {
                     if let Some(left_right) = left.right.get_if_black() {
-                        return Link::new(Arc::new(Node {
-                            color: Color::Black,
-                            key: left.key.clone(),
-                            value: left.value.clone(),
-                            left: left.left.clone(),
-                            right: Color::Black.balance(left_right.key.clone(),
-                                                        left_right.value.clone(),
-                                                        left_right.left.clone(),
-                                                        Link::Node(Arc::new(Node {
-                                                            color: Color::Red,
-                                                            key: key,
-                                                           value: value,
-                                                            left: left_right.right.clone(),
-                                                            right: right.double_black_to_black(),
-                                                        }))),
-                        }))
+                        let right = Link::node(Color::Red,
+                                               key,
+                                               value,
+                                               left_right.right.clone(),
+                                               right.double_black_to_black());
+                        return Link::node(Color::Black,
+                                          left.key.clone(),
+                                          left.value.clone(),
+                                          left.left.clone(),
+                                          Color::Black.balance(left_right.key.clone(),
+                                                               left_right.value.clone(),
+                                                               left_right.left.clone(),
+                                                               right))
                     }
                 }
             }
         }
 
-        Link::Node(Arc::new(Node {
-            key: key,
-            value: value,
-            left: left,
-            right: right,
-            color: self,
-        }))
+        Link::node(self, key, value, left, right)
     }
 
     fn is_red(self) -> bool {
@@ -434,12 +2714,15 @@ impl Color {
     }
 }
 
-pub struct Iter<'a, K, V> where K: 'a, V: 'a {
-    start: Option<&'a Arc<Node<K, V>>>,
-    stack: Vec<&'a Arc<Node<K, V>>>,
+pub struct Iter<'a, K, V, M> where K: 'a, V: 'a, M: Monoid<K, V> {
+    start: Option<&'a Arc<Node<K, V, M>>>,
+    stack: Vec<&'a Arc<Node<K, V, M>>>,
 }
 
-impl<'a, K, V> Iterator for Iter<'a, K, V> where K: Clone + PartialOrd + Ord, V: Clone {
+impl<'a, K, V, M> Iterator for Iter<'a, K, V, M>
+                               where K: Clone + PartialOrd + Ord,
+                                     V: Clone,
+                                     M: Monoid<K, V> {
     type Item = (&'a K, &'a V);
 
     fn next(&mut self) -> Option<(&'a K, &'a V)> {
@@ -462,24 +2745,490 @@ impl<'a, K, V> Iterator for Iter<'a, K, V> where K: Clone + PartialOrd + Ord, V:
     }
 }
 
-impl<K, V> FromIterator<(K, V)> for Tree<K, V> where K: Clone + PartialOrd + Ord, V: Clone {
-    fn from_iter<T>(iter: T) -> Tree<K, V> where T: IntoIterator<Item = (K, V)> {
-        let mut tree = Tree::new();
-        for (key, value) in iter {
-            tree = tree.insert(key, value)
+// Like `Iter`, but in descending key order.
+struct RevIter<'a, K, V, M> where K: 'a, V: 'a, M: Monoid<K, V> {
+    start: Option<&'a Arc<Node<K, V, M>>>,
+    stack: Vec<&'a Arc<Node<K, V, M>>>,
+}
+
+impl<'a, K, V, M> Iterator for RevIter<'a, K, V, M>
+                               where K: Clone + PartialOrd + Ord,
+                                     V: Clone,
+                                     M: Monoid<K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        let mut current = self.start.take();
+        while let Some(node) = current {
+            self.stack.push(node);
+            current = node.right.to_option()
+        }
+        let node = self.stack.pop()?;
+        self.start = node.left.to_option();
+        Some((&node.key, &node.value))
+    }
+}
+
+struct OutsideInIter<'a, K, V, M> where K: 'a, V: 'a, M: Monoid<K, V> {
+    front: Iter<'a, K, V, M>,
+    back: RevIter<'a, K, V, M>,
+    remaining: usize,
+}
+
+impl<'a, K, V, M> Iterator for OutsideInIter<'a, K, V, M>
+                               where K: Clone + PartialOrd + Ord,
+                                     V: Clone,
+                                     M: Monoid<K, V> {
+    type Item = OutsideIn<'a, K, V>;
+
+    fn next(&mut self) -> Option<OutsideIn<'a, K, V>> {
+        match self.remaining {
+            0 => None,
+            1 => {
+                self.remaining = 0;
+                self.front.next().map(OutsideIn::Middle)
+            }
+            _ => {
+                self.remaining -= 2;
+                Some(OutsideIn::Pair(self.front.next()?, self.back.next()?))
+            }
+        }
+    }
+}
+
+// The iterator behind `Tree::new_entries_since`.
+struct NewEntries<'a, K, V, M> where K: 'a, V: 'a, M: Monoid<K, V> {
+    // The work left to do, with the next item to yield on top.
+    pending: Vec<NewEntriesFrame<'a, K, V, M>>,
+}
+
+enum NewEntriesFrame<'a, K, V, M> where K: 'a, V: 'a, M: Monoid<K, V> {
+    // A subtree of the new tree, the subtree of the base that holds every base key that could
+    // match it, and the keys of its nearest ancestors, which bound its keys strictly.
+    Subtree(&'a Link<K, V, M>, &'a Link<K, V, M>, Option<&'a K>, Option<&'a K>),
+    // An entry of the new tree to yield if its key is absent from the given base subtree.
+    Entry(&'a Node<K, V, M>, &'a Link<K, V, M>),
+}
+
+impl<'a, K, V, M> Iterator for NewEntries<'a, K, V, M>
+                               where K: Clone + PartialOrd + Ord,
+                                     V: Clone,
+                                     M: Monoid<K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        loop {
+            let (link, mut base, lower, upper) = match self.pending.pop()? {
+                NewEntriesFrame::Entry(node, base) => {
+                    if base.get_by(|key| node.key.cmp(key)).is_none() {
+                        return Some((&node.key, &node.value))
+                    }
+                    continue
+                }
+                NewEntriesFrame::Subtree(link, base, lower, upper) => (link, base, lower, upper),
+            };
+            let node = match *link {
+                Link::Empty => continue,
+                Link::Node(ref node) => node,
+            };
+            // Find the smallest subtree of `base` holding all of its keys within the bounds. If
+            // it is this very subtree, nothing here is new.
+            while let Link::Node(ref base_node) = *base {
+                if lower.is_some_and(|lower| base_node.key <= *lower) {
+                    base = &base_node.right
+                } else if upper.is_some_and(|upper| base_node.key >= *upper) {
+                    base = &base_node.left
+                } else {
+                    break
+                }
+            }
+            if link.ptr_eq(base) {
+                continue
+            }
+            self.pending.push(NewEntriesFrame::Subtree(&node.right, base, Some(&node.key), upper));
+            self.pending.push(NewEntriesFrame::Entry(node, base));
+            self.pending.push(NewEntriesFrame::Subtree(&node.left, base, lower, Some(&node.key)))
+        }
+    }
+}
+
+pub struct GroupByKey<'a, K, V, M, F, G> where K: 'a, V: 'a, M: Monoid<K, V> {
+    iter: Iter<'a, K, V, M>,
+    key_fn: F,
+    pending: Option<(G, (&'a K, &'a V))>,
+}
+
+impl<'a, K, V, M, F, G> Iterator for GroupByKey<'a, K, V, M, F, G>
+                                  where K: Clone + PartialOrd + Ord,
+                                        V: Clone,
+                                        F: FnMut(&K) -> G,
+                                        G: PartialEq, M: Monoid<K, V> {
+    type Item = (G, Vec<(&'a K, &'a V)>);
+
+    fn next(&mut self) -> Option<(G, Vec<(&'a K, &'a V)>)> {
+        let (group, first) = match self.pending.take() {
+            Some(pending) => pending,
+            None => {
+                let entry = self.iter.next()?;
+                ((self.key_fn)(entry.0), entry)
+            }
+        };
+        let mut entries = vec![first];
+        for entry in &mut self.iter {
+            let next_group = (self.key_fn)(entry.0);
+            if next_group != group {
+                self.pending = Some((next_group, entry));
+                break
+            }
+            entries.push(entry)
+        }
+        Some((group, entries))
+    }
+}
+
+// The next entry of one of the trees being merged by `merge_iters`, along with the position of
+// that tree. Heads are ordered so that the greatest is the one with the smallest key, and among
+// equal keys, the one from the latest tree.
+struct MergeHead<'a, K, V, M> where K: 'a, V: 'a, M: Monoid<K, V> {
+    entry: (&'a K, &'a V),
+    index: usize,
+    iter: Iter<'a, K, V, M>,
+}
+
+impl<'a, K, V, M> Ord for MergeHead<'a, K, V, M> where K: Ord, M: Monoid<K, V> {
+    fn cmp(&self, other: &MergeHead<'a, K, V, M>) -> Ordering {
+        other.entry.0.cmp(self.entry.0).then(self.index.cmp(&other.index))
+    }
+}
+
+impl<'a, K, V, M> PartialOrd for MergeHead<'a, K, V, M> where K: Ord, M: Monoid<K, V> {
+    fn partial_cmp(&self, other: &MergeHead<'a, K, V, M>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a, K, V, M> PartialEq for MergeHead<'a, K, V, M> where K: Ord, M: Monoid<K, V> {
+    fn eq(&self, other: &MergeHead<'a, K, V, M>) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<'a, K, V, M> Eq for MergeHead<'a, K, V, M> where K: Ord, M: Monoid<K, V> {}
+
+struct Merge<'a, K, V, M> where K: 'a, V: 'a, M: Monoid<K, V> {
+    heads: BinaryHeap<MergeHead<'a, K, V, M>>,
+}
+
+impl<'a, K, V, M> Merge<'a, K, V, M> where K: Clone + PartialOrd + Ord, V: Clone, M: Monoid<K, V> {
+    // Removes the greatest head and, if its tree has more entries, pushes back its successor.
+    fn advance(&mut self) -> Option<(&'a K, &'a V)> {
+        let mut head = self.heads.pop()?;
+        let entry = head.entry;
+        if let Some(next) = head.iter.next() {
+            head.entry = next;
+            self.heads.push(head)
+        }
+        Some(entry)
+    }
+}
+
+impl<'a, K, V, M> Iterator for Merge<'a, K, V, M>
+                                 where K: Clone + PartialOrd + Ord,
+                                       V: Clone,
+                                       M: Monoid<K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        let entry = self.advance()?;
+        while self.heads.peek().map(|head| head.entry.0) == Some(entry.0) {
+            self.advance();
+        }
+        Some(entry)
+    }
+}
+
+pub struct Chunks<'a, K, V, M> where K: 'a, V: 'a, M: Monoid<K, V> {
+    iter: Iter<'a, K, V, M>,
+    size: usize,
+}
+
+impl<'a, K, V, M> Iterator for Chunks<'a, K, V, M>
+                               where K: Clone + PartialOrd + Ord,
+                                     V: Clone,
+                                     M: Monoid<K, V> {
+    type Item = Vec<(&'a K, &'a V)>;
+
+    fn next(&mut self) -> Option<Vec<(&'a K, &'a V)>> {
+        let chunk: Vec<_> = (&mut self.iter).take(self.size).collect();
+        if chunk.is_empty() {
+            None
+        } else {
+            Some(chunk)
+        }
+    }
+}
+
+struct MergeJoin<'a, K, V, W, M, N> where K: 'a + Clone + PartialOrd + Ord,
+                                           V: 'a + Clone,
+                                           W: 'a + Clone,
+                                           M: Monoid<K, V>,
+                                           N: Monoid<K, W> {
+    left: Peekable<Iter<'a, K, V, M>>,
+    right: Peekable<Iter<'a, K, W, N>>,
+}
+
+impl<'a, K, V, W, M, N> Iterator for MergeJoin<'a, K, V, W, M, N>
+                                     where K: Clone + PartialOrd + Ord,
+                                           V: Clone,
+                                           W: Clone,
+                                           M: Monoid<K, V>,
+                                           N: Monoid<K, W> {
+    type Item = MergeSide<'a, K, V, W>;
+
+    fn next(&mut self) -> Option<MergeSide<'a, K, V, W>> {
+        let ordering = match (self.left.peek(), self.right.peek()) {
+            (None, None) => return None,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(&(left, _)), Some(&(right, _))) => left.cmp(right),
+        };
+        match ordering {
+            Ordering::Less => self.left.next().map(|(key, value)| MergeSide::Left(key, value)),
+            Ordering::Greater => self.right.next().map(|(key, value)| MergeSide::Right(key, value)),
+            Ordering::Equal => {
+                let ((key, left), (_, right)) = (self.left.next()?, self.right.next()?);
+                Some(MergeSide::Both(key, left, right))
+            }
+        }
+    }
+}
+
+/// Accumulates entries and bulk-builds a tree from them in O(n log n), without producing any
+/// intermediate trees. As with `collect`, the last value pushed for a key wins.
+pub struct TreeBuilder<K, V, M = ()> where M: Monoid<K, V> {
+    entries: Vec<(K, V)>,
+    monoid: PhantomData<M>,
+}
+
+impl<K, V, M> TreeBuilder<K, V, M> where K: Clone + PartialOrd + Ord, V: Clone, M: Monoid<K, V> {
+    pub fn push(&mut self, key: K, value: V) {
+        self.entries.push((key, value))
+    }
+
+    pub fn build(self) -> Tree<K, V, M> {
+        Tree::from_unsorted_vec(self.entries)
+    }
+}
+
+impl<K, V, M> Default for TreeBuilder<K, V, M> where M: Monoid<K, V> {
+    fn default() -> TreeBuilder<K, V, M> {
+        TreeBuilder {
+            entries: vec![],
+            monoid: PhantomData,
+        }
+    }
+}
+
+impl<K, V, M> Extend<(K, V)> for TreeBuilder<K, V, M> where M: Monoid<K, V> {
+    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item = (K, V)> {
+        self.entries.extend(iter)
+    }
+}
+
+/// Records insertions, removals, and updates for `Tree::edit` to apply in one pass. Edits to the
+/// same key take effect in the order they were made.
+pub struct Editor<'a, K, V> {
+    edits: Vec<(K, Edit<'a, V>)>,
+}
+
+enum Edit<'a, V> {
+    Insert(V),
+    Remove,
+    Update(Box<dyn FnOnce(&V) -> V + 'a>),
+}
+
+impl<'a, K, V> Editor<'a, K, V> {
+    /// Inserts `value` at `key`, replacing any value already there.
+    pub fn insert(&mut self, key: K, value: V) {
+        self.edits.push((key, Edit::Insert(value)))
+    }
+
+    /// Removes `key`, if present.
+    pub fn remove(&mut self, key: K) {
+        self.edits.push((key, Edit::Remove))
+    }
+
+    /// Replaces the value at `key` with `f(&value)`, if the key is present when this edit takes
+    /// effect.
+    pub fn update<F>(&mut self, key: K, f: F) where F: FnOnce(&V) -> V + 'a {
+        self.edits.push((key, Edit::Update(Box::new(f))))
+    }
+}
+
+impl<'a, V> Edit<'a, V> {
+    fn apply(self, value: Option<V>) -> Option<V> {
+        match self {
+            Edit::Insert(value) => Some(value),
+            Edit::Remove => None,
+            Edit::Update(f) => value.map(|value| f(&value)),
+        }
+    }
+}
+
+/// An owning iterator over the entries of a tree in key order, from either end.
+///
+/// A node is moved out of if this iterator holds the only reference to it, and cloned otherwise.
+pub struct IntoIter<K, V, M> where M: Monoid<K, V> {
+    pending: VecDeque<Pending<K, V, M>>,
+    remaining: usize,
+}
+
+// The not-yet-yielded part of an owning iteration, in key order: entries taken out of nodes
+// already visited from one end or the other, and whole subtrees not yet visited from either.
+enum Pending<K, V, M> where M: Monoid<K, V> {
+    Entry(K, V),
+    Subtree(Link<K, V, M>),
+}
+
+impl<K, V, M> IntoIter<K, V, M> where K: Clone, V: Clone, M: Monoid<K, V> {
+    // Takes ownership of the root of `link`, cloning it if it is shared.
+    fn unwrap_node(link: Link<K, V, M>) -> Option<Node<K, V, M>> {
+        match link {
+            Link::Empty => None,
+            Link::Node(node) => Some(Arc::try_unwrap(node).unwrap_or_else(|node| (*node).clone())),
+        }
+    }
+}
+
+impl<K, V, M> Iterator for IntoIter<K, V, M> where K: Clone, V: Clone, M: Monoid<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        loop {
+            match self.pending.pop_front()? {
+                Pending::Entry(key, value) => {
+                    self.remaining -= 1;
+                    return Some((key, value))
+                }
+                Pending::Subtree(link) => {
+                    if let Some(node) = IntoIter::unwrap_node(link) {
+                        self.pending.push_front(Pending::Subtree(node.right));
+                        self.pending.push_front(Pending::Entry(node.key, node.value));
+                        self.pending.push_front(Pending::Subtree(node.left))
+                    }
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V, M> DoubleEndedIterator for IntoIter<K, V, M>
+                                      where K: Clone,
+                                            V: Clone,
+                                            M: Monoid<K, V> {
+    fn next_back(&mut self) -> Option<(K, V)> {
+        loop {
+            match self.pending.pop_back()? {
+                Pending::Entry(key, value) => {
+                    self.remaining -= 1;
+                    return Some((key, value))
+                }
+                Pending::Subtree(link) => {
+                    if let Some(node) = IntoIter::unwrap_node(link) {
+                        self.pending.push_back(Pending::Subtree(node.left));
+                        self.pending.push_back(Pending::Entry(node.key, node.value));
+                        self.pending.push_back(Pending::Subtree(node.right))
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<K, V, M> ExactSizeIterator for IntoIter<K, V, M> where K: Clone, V: Clone, M: Monoid<K, V> {}
+
+impl<K, V, M> IntoIterator for Tree<K, V, M>
+                               where K: Clone + PartialOrd + Ord,
+                                     V: Clone,
+                                     M: Monoid<K, V> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V, M>;
+
+    fn into_iter(self) -> IntoIter<K, V, M> {
+        let remaining = self.len();
+        let mut pending = VecDeque::new();
+        pending.push_back(Pending::Subtree(self.root));
+        IntoIter {
+            pending: pending,
+            remaining: remaining,
+        }
+    }
+}
+
+impl<'a, K, V, M> IntoIterator for &'a Tree<K, V, M>
+                                   where K: Clone + PartialOrd + Ord,
+                                         V: Clone,
+                                         M: Monoid<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V, M>;
+
+    fn into_iter(self) -> Iter<'a, K, V, M> {
+        self.iter()
+    }
+}
+
+impl<K, V, M> Default for Tree<K, V, M>
+                          where K: Clone + PartialOrd + Ord,
+                                V: Clone,
+                                M: Monoid<K, V> {
+    fn default() -> Tree<K, V, M> {
+        Tree {
+            root: Link::Empty,
+            version: 0,
+        }
+    }
+}
+
+impl<K, V, M> FromIterator<(K, V)> for Tree<K, V, M>
+                                       where K: Clone + PartialOrd + Ord,
+                                             V: Clone,
+                                             M: Monoid<K, V> {
+    fn from_iter<T>(iter: T) -> Tree<K, V, M> where T: IntoIterator<Item = (K, V)> {
+        Tree::with_capacity_hint(iter, 0)
+    }
+}
+
+/// Looks up a key, panicking with its `Debug` representation if it is absent.
+impl<K, V, M, Q> Index<&Q> for Tree<K, V, M>
+                                 where K: Clone + PartialOrd + Ord + Borrow<Q>,
+                                       V: Clone,
+                                       M: Monoid<K, V>,
+                                       Q: ?Sized + Ord + Debug {
+    type Output = V;
+
+    fn index(&self, key: &Q) -> &V {
+        match self.get(key) {
+            Some(value) => value,
+            None => panic!("no entry found for key `{:?}`", key),
         }
-        tree
     }
 }
 
-impl<K, V> Debug for Tree<K, V> where K: Clone + PartialOrd + Ord + Debug, V: Clone + Debug {
+impl<K, V, M> Debug for Tree<K, V, M>
+                        where K: Clone + PartialOrd + Ord + Debug,
+                              V: Clone + Debug,
+                              M: Monoid<K, V> {
     fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
-        try!(write!(formatter, "["));
+        write!(formatter, "[")?;
         let mut iter = self.iter();
         if let Some(pair) = iter.next() {
-            try!(pair.fmt(formatter));
+            pair.fmt(formatter)?;
             for pair in iter {
-                try!(write!(formatter, ", {:?}", pair))
+                write!(formatter, ", {:?}", pair)?
             }
         }
         write!(formatter, "]")
@@ -0,0 +1,115 @@
+use rayon;
+use rayon::iter::{FromParallelIterator, IntoParallelIterator, ParallelIterator};
+use rayon::iter::plumbing::{Folder, UnindexedConsumer, UnindexedProducer, bridge_unindexed};
+use rayon::slice::ParallelSliceMut;
+
+use {Link, Monoid, Tree};
+
+impl<K, V, M> Tree<K, V, M>
+              where K: Clone + PartialOrd + Ord + Send + Sync,
+                    V: Clone + Send + Sync,
+                    M: Monoid<K, V>,
+                    M::Summary: Send + Sync {
+    /// Returns a parallel iterator over the entries. The work is divided by handing the left and
+    /// right subtrees of a node to different tasks, which is safe because trees are immutable.
+    pub fn par_iter<'a>(&'a self) -> ParIter<'a, K, V, M> {
+        ParIter {
+            root: &self.root,
+        }
+    }
+
+    /// Folds the entries in parallel. Each task folds its share of the entries in key order with
+    /// `fold`, starting from a copy of `identity`, and the results of neighboring tasks are merged
+    /// with `combine`, which must be associative and have `identity` as its identity.
+    pub fn par_fold<B, F, G>(&self, identity: B, fold: F, combine: G) -> B
+                             where B: Clone + Send,
+                                   F: Fn(B, (&K, &V)) -> B + Sync,
+                                   G: Fn(B, B) -> B + Sync {
+        par_fold(&self.root, identity, &fold, &combine)
+    }
+}
+
+// Subtrees with at most this many entries are folded on the current thread, since handing them to
+// another task would cost more than it saves.
+const SEQUENTIAL_FOLD_LEN: usize = 1024;
+
+fn par_fold<K, V, M, B, F, G>(link: &Link<K, V, M>, identity: B, fold: &F, combine: &G) -> B
+                              where K: Clone + PartialOrd + Ord + Send + Sync,
+                                    V: Clone + Send + Sync,
+                                    M: Monoid<K, V>,
+                                    M::Summary: Send + Sync,
+                                    B: Clone + Send,
+                                    F: Fn(B, (&K, &V)) -> B + Sync,
+                                    G: Fn(B, B) -> B + Sync {
+    match *link {
+        Link::Node(ref node) if node.size > SEQUENTIAL_FOLD_LEN => {
+            let right_identity = identity.clone();
+            let (left, right) =
+                rayon::join(|| par_fold(&node.left, identity, fold, combine),
+                            || par_fold(&node.right, right_identity, fold, combine));
+            combine(fold(left, (&node.key, &node.value)), right)
+        }
+        _ => link.iter().fold(identity, fold),
+    }
+}
+
+pub struct ParIter<'a, K, V, M> where K: 'a, V: 'a, M: Monoid<K, V> {
+    root: &'a Link<K, V, M>,
+}
+
+impl<'a, K, V, M> ParallelIterator for ParIter<'a, K, V, M>
+                                    where K: Clone + PartialOrd + Ord + Send + Sync,
+                                          V: Clone + Send + Sync,
+                                          M: Monoid<K, V>,
+                                          M::Summary: Send + Sync {
+    type Item = (&'a K, &'a V);
+
+    fn drive_unindexed<C>(self, consumer: C) -> C::Result where C: UnindexedConsumer<Self::Item> {
+        bridge_unindexed(SubtreeProducer { first: None, link: self.root }, consumer)
+    }
+}
+
+// An optional entry followed by a subtree whose keys all come after it.
+struct SubtreeProducer<'a, K, V, M> where K: 'a, V: 'a, M: Monoid<K, V> {
+    first: Option<(&'a K, &'a V)>,
+    link: &'a Link<K, V, M>,
+}
+
+impl<'a, K, V, M> UnindexedProducer for SubtreeProducer<'a, K, V, M>
+                                     where K: Clone + PartialOrd + Ord + Send + Sync,
+                                           V: Clone + Send + Sync,
+                                           M: Monoid<K, V>,
+                                           M::Summary: Send + Sync {
+    type Item = (&'a K, &'a V);
+
+    fn split(self) -> (SubtreeProducer<'a, K, V, M>, Option<SubtreeProducer<'a, K, V, M>>) {
+        match *self.link {
+            Link::Empty => (self, None),
+            Link::Node(ref node) => {
+                (SubtreeProducer { first: self.first, link: &node.left },
+                 Some(SubtreeProducer { first: Some((&node.key, &node.value)), link: &node.right }))
+            }
+        }
+    }
+
+    fn fold_with<F>(self, folder: F) -> F where F: Folder<(&'a K, &'a V)> {
+        folder.consume_iter(self.first.into_iter().chain(self.link.iter()))
+    }
+}
+
+/// Collects entries in parallel, sorts them in parallel, and bulk-builds the tree.
+///
+/// As with the sequential `collect`, when a key appears more than once the value that comes last
+/// in the parallel iterator's order wins. That order is deterministic for iterators derived from
+/// ordered sources such as slices, ranges, or `par_iter`, but not for, say, `par_bridge`.
+impl<K, V, M> FromParallelIterator<(K, V)> for Tree<K, V, M>
+                                        where K: Clone + PartialOrd + Ord + Send + Sync,
+                                              V: Clone + Send + Sync,
+                                              M: Monoid<K, V>,
+                                              M::Summary: Send + Sync {
+    fn from_par_iter<I>(iter: I) -> Tree<K, V, M> where I: IntoParallelIterator<Item = (K, V)> {
+        let mut entries: Vec<(K, V)> = iter.into_par_iter().collect();
+        entries.par_sort_by(|a, b| a.0.cmp(&b.0));
+        Tree::from_stably_sorted_vec(entries)
+    }
+}
@@ -1,5 +1,23 @@
-use Tree;
+use {DuplicateKey, Link, MergeSide, Monoid, Node, OccupiedError, Op, OrderedMap, OutsideIn,
+     StructureError, TooDeep, Tree};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::mem;
+use quickcheck::{Arbitrary, Gen};
 use rand::{self, Rng};
+use std::ops::Bound;
+use std::rc::Rc;
+use std::sync::Arc;
+
+// Builds a set-like tree from `keys`, along with the `BTreeSet` it should agree with.
+fn set_tree(keys: Vec<u8>) -> (Tree<u8, ()>, BTreeSet<u8>) {
+    let reference = keys.iter().cloned().collect();
+    (keys.into_iter().map(|key| (key, ())).collect(), reference)
+}
 
 quickcheck! {
     fn insert_then_iterate(keys: Vec<u32>) -> bool {
@@ -16,5 +34,1306 @@ quickcheck! {
         let results: Vec<u32> = tree.iter().map(|(k, _)| *k).collect();
         reference == results
     }
+
+    fn collect_keeps_last_duplicate(pairs: Vec<(u8, u32)>) -> bool {
+        let reference: BTreeMap<u8, u32> = pairs.iter().cloned().collect();
+        let tree: Tree<u8, u32> = pairs.into_iter().collect();
+        tree.iter().map(|(k, v)| (*k, *v)).eq(reference.into_iter())
+    }
+
+    fn agrees_with_btreemap(ops: Vec<(u8, u8, u32)>) -> bool {
+        let (mut tree, mut reference) = (Tree::new(), BTreeMap::new());
+        for (op, key, value) in ops {
+            match op % 3 {
+                0 => {
+                    tree = tree.insert(key, value);
+                    reference.insert(key, value);
+                }
+                1 => {
+                    tree = tree.remove(&key);
+                    reference.remove(&key);
+                }
+                _ => {
+                    if tree.get(&key) != reference.get(&key) {
+                        return false
+                    }
+                }
+            }
+            if tree.len() != reference.len() || tree.is_empty() != reference.is_empty() {
+                return false
+            }
+        }
+        tree.iter().eq(reference.iter())
+    }
+
+    fn builder_matches_collect(first: Vec<(u8, u32)>, second: Vec<(u8, u32)>) -> bool {
+        let mut builder = Tree::builder();
+        for &(key, value) in &first {
+            builder.push(key, value)
+        }
+        builder.extend(second.iter().cloned());
+        let tree = builder.build();
+        let collected: Tree<u8, u32> = first.into_iter().chain(second).collect();
+        tree.check_invariants() && tree.iter().eq(collected.iter())
+    }
+
+    fn collect_refs_is_index_aligned(pairs: Vec<(u8, u32)>) -> bool {
+        let tree: Tree<u8, u32> = pairs.into_iter().collect();
+        let (keys, values) = tree.collect_refs();
+        keys.into_iter().zip(values).eq(tree.iter())
+    }
+
+    fn append_sorted_matches_insert(keys: Vec<u8>, items: Vec<(u8, u32)>, fast: bool) -> bool {
+        let tree: Tree<u16, u32> = keys.into_iter().map(|k| (k as u16, 0)).collect();
+        let items: Vec<(u16, u32)> = if fast {
+            // Sorted, distinct, and above every key in the tree.
+            let items: BTreeMap<u16, u32> =
+                items.into_iter().map(|(k, v)| (k as u16 + 256, v)).collect();
+            items.into_iter().collect()
+        } else {
+            items.into_iter().map(|(k, v)| (k as u16, v)).collect()
+        };
+        let expected = items.iter().fold(tree.clone(), |tree, &(k, v)| tree.insert(k, v));
+        let appended = tree.append_sorted(items);
+        appended.check_invariants() && appended.iter().eq(expected.iter())
+    }
+
+    fn level_order_round_trips_shape(keys: Vec<u8>) -> bool {
+        let mut tree = Tree::new();
+        for key in keys {
+            tree = tree.insert(key, key as u32)
+        }
+        let level_order = tree.to_level_order();
+        let rebuilt: Tree<u8, u32> = Tree::from_level_order(level_order.clone()).unwrap();
+        rebuilt.check_invariants() && rebuilt.to_level_order() == level_order
+    }
+
+    fn compact_is_valid_and_minimal_height(keys: Vec<u32>) -> bool {
+        let tree: Tree<u32, u32> = keys.iter().map(|&k| (k, k)).collect();
+        let tree = keys.iter().fold(tree, |tree, &k| tree.insert(k / 2, k));
+        let compacted = tree.compact();
+        let len = compacted.iter().count();
+        let mut minimal_height = 0;
+        while (1 << minimal_height) < len + 1 {
+            minimal_height += 1
+        }
+        compacted.check_invariants() && compacted.root.height() == minimal_height &&
+            compacted.iter().eq(tree.iter()) &&
+            (len == 0 || !compacted.ptr_eq(&tree))
+    }
+
+    fn remove_subset_preserves_invariants(keys: Vec<u8>, removed: Vec<u8>) -> bool {
+        let mut tree = Tree::new();
+        for &key in &keys {
+            tree = tree.insert(key, key);
+            if !tree.check_invariants() {
+                return false
+            }
+        }
+        for key in &removed {
+            tree = tree.remove(key);
+            let (red, black, double_black) = tree.color_counts();
+            if !tree.check_invariants() || double_black != 0 || red + black != tree.len() {
+                return false
+            }
+        }
+        let removed: BTreeSet<u8> = removed.into_iter().collect();
+        let expected: BTreeSet<u8> = keys.into_iter().filter(|k| !removed.contains(k)).collect();
+        tree.iter().map(|(k, _)| k).eq(expected.iter())
+    }
+
+    fn insert_then_remove_restores_entries(keys: Vec<u32>, key: u32) -> bool {
+        let tree: Tree<u32, u32> = keys.into_iter().filter(|&k| k != key).map(|k| (k, k)).collect();
+        let round_tripped = tree.insert(key, key).remove(&key);
+        round_tripped.check_invariants() && round_tripped.iter().eq(tree.iter())
+    }
+
+    fn edit_matches_sequential_ops(keys: Vec<u8>, ops: Vec<(u8, u8, u32)>) -> bool {
+        let tree: Tree<u8, u32> = keys.into_iter().map(|key| (key, key as u32)).collect();
+        let mut reference: BTreeMap<u8, u32> = tree.iter().map(|(&k, &v)| (k, v)).collect();
+        let edited = tree.edit(|editor| {
+            for &(op, key, value) in &ops {
+                match op % 3 {
+                    0 => editor.insert(key, value),
+                    1 => editor.remove(key),
+                    _ => editor.update(key, move |old| old.wrapping_add(value)),
+                }
+            }
+        });
+        for (op, key, value) in ops {
+            match op % 3 {
+                0 => {
+                    reference.insert(key, value);
+                }
+                1 => {
+                    reference.remove(&key);
+                }
+                _ => {
+                    if let Some(old) = reference.get_mut(&key) {
+                        *old = old.wrapping_add(value)
+                    }
+                }
+            }
+        }
+        edited.check_invariants() && edited.iter().eq(reference.iter())
+    }
+
+    fn set_at_replaces_one_value(keys: Vec<u8>, index: usize) -> bool {
+        let tree: Tree<u8, u32> = keys.into_iter().map(|key| (key, key as u32)).collect();
+        if tree.is_empty() {
+            return true
+        }
+        let index = index % tree.len();
+        let updated = tree.set_at(index, 1000);
+        updated.check_invariants() &&
+            updated.iter().enumerate().zip(tree.iter()).all(|((i, (k, &v)), (key, &value))| {
+                k == key && v == if i == index { 1000 } else { value }
+            })
+    }
+
+    fn remove_at_matches_vec_remove(keys: Vec<u8>, index: usize) -> bool {
+        let tree: Tree<u8, u8> = keys.into_iter().map(|key| (key, key)).collect();
+        let mut entries: Vec<(u8, u8)> = tree.iter().map(|(&k, &v)| (k, v)).collect();
+        if tree.remove_at(tree.len()).1.is_some() {
+            return false
+        }
+        let index = index % (tree.len() + 1);
+        let (removed_from, removed) = tree.remove_at(index);
+        let expected = if index < entries.len() { Some(entries.remove(index)) } else { None };
+        removed_from.check_invariants() && removed == expected &&
+            removed_from.iter().map(|(&k, &v)| (k, v)).eq(entries)
+    }
+
+    fn key_hash_ignores_values_and_insertion_order(pairs: Vec<(u8, u32)>) -> bool {
+        let hash = |tree: &Tree<u8, u32>| {
+            let mut hasher = DefaultHasher::new();
+            tree.key_hash(&mut hasher);
+            hasher.finish()
+        };
+        let tree: Tree<u8, u32> = pairs.iter().cloned().collect();
+        let reversed = pairs.iter().rev().fold(Tree::new(), |tree, &(k, v)| tree.insert(k, !v));
+        hash(&tree) == hash(&reversed)
+    }
+
+    fn ops_agree_with_btreemap(ops: Vec<Op<u8, u8>>) -> bool {
+        ops_agree_with_btreemap_throughout(ops)
+    }
+
+    fn decoded_ops_agree_with_btreemap(data: Vec<u8>) -> bool {
+        let ops = Op::decode(&data);
+        ops.len() == data.len() / 2 && ops_agree_with_btreemap_throughout(ops)
+    }
+}
+
+quickcheck! {
+    fn subset_matches_btreeset(a: Vec<u8>, b: Vec<u8>) -> bool {
+        let ((a, a_keys), (b, b_keys)) = (set_tree(a), set_tree(b));
+        a.is_subset(&b) == a_keys.is_subset(&b_keys) &&
+            a.is_superset(&b) == a_keys.is_superset(&b_keys) &&
+            a.is_subset(&a)
+    }
+
+    fn disjoint_iff_intersection_is_empty(a: Vec<u8>, b: Vec<u8>) -> bool {
+        let ((a, a_keys), (b, b_keys)) = (set_tree(a), set_tree(b));
+        a.is_disjoint(&b) == a_keys.intersection(&b_keys).next().is_none()
+    }
+
+    fn merge_iters_lets_later_trees_win(layers: Vec<Vec<(u8, u32)>>) -> bool {
+        let trees: Vec<Tree<u8, u32>> =
+            layers.iter().map(|layer| layer.iter().cloned().collect()).collect();
+        let reference: BTreeMap<u8, u32> = layers.into_iter().flat_map(|layer| {
+            let layer: BTreeMap<u8, u32> = layer.into_iter().collect();
+            layer
+        }).collect();
+        Tree::merge_iters(&trees).eq(reference.iter())
+    }
+
+    fn concat_matches_union_of_disjoint_trees(keys: Vec<u8>, split: u8) -> bool {
+        let (tree, _) = set_tree(keys);
+        let lower = tree.retain_range(..split);
+        let higher = tree.retain_range(split..);
+        let joined = lower.concat(&higher);
+        joined.check_invariants() && joined.iter().eq(tree.iter())
+    }
+
+    fn new_entries_since_is_key_difference(base: Vec<u8>, inserted: Vec<u8>, removed: Vec<u8>)
+                                           -> bool {
+        let (base, _) = set_tree(base);
+        let mut derived = inserted.into_iter().fold(base.clone(), |tree, k| tree.insert(k, ()));
+        derived = removed.iter().fold(derived, |tree, k| tree.remove(k));
+        let expected = derived.iter().filter(|&(key, _)| base.get(key).is_none());
+        derived.new_entries_since(&base).eq(expected)
+    }
+
+    fn merge_join_classifies_every_key(left: Vec<u8>, right: Vec<u8>) -> bool {
+        let left: Tree<u8, u32> = left.into_iter().map(|key| (key, key as u32)).collect();
+        let right: Tree<u8, char> = right.into_iter().map(|key| (key, 'r')).collect();
+        let keys: BTreeSet<u8> =
+            left.iter().map(|(&k, _)| k).chain(right.iter().map(|(&k, _)| k)).collect();
+        left.merge_join(&right).zip(keys.iter()).all(|(side, key)| {
+            match side {
+                MergeSide::Both(k, v, w) => {
+                    k == key && left.get(k) == Some(v) && right.get(k) == Some(w)
+                }
+                MergeSide::Left(k, v) => {
+                    k == key && left.get(k) == Some(v) && right.get(k).is_none()
+                }
+                MergeSide::Right(k, w) => {
+                    k == key && left.get(k).is_none() && right.get(k) == Some(w)
+                }
+            }
+        }) && left.merge_join(&right).count() == keys.len()
+    }
+
+    fn merge_all_lets_later_trees_win(layers: Vec<Vec<(u8, u32)>>) -> bool {
+        let trees: Vec<Tree<u8, u32>> =
+            layers.iter().map(|layer| layer.iter().cloned().collect()).collect();
+        let mut reference = BTreeMap::new();
+        for tree in &trees {
+            reference.extend(tree.iter().map(|(&k, &v)| (k, v)))
+        }
+        let merged = Tree::merge_all(&trees);
+        merged.check_invariants() && merged.iter().eq(reference.iter())
+    }
+
+    fn ordered_map_agrees_for_tree_and_btreemap(keys: Vec<u8>, lo: u8, hi: u8) -> bool {
+        let (lo, hi) = (cmp::min(lo, hi), cmp::max(lo, hi));
+        let tree: Tree<u8, u32> = keys.iter().map(|&k| (k, k as u32)).collect();
+        let reference: BTreeMap<u8, u32> = keys.iter().map(|&k| (k, k as u32)).collect();
+        ordered_map_summary(&tree, lo, hi) == ordered_map_summary(&reference, lo, hi)
+    }
+}
+
+#[test]
+fn ptr_eq_is_identity_not_equality() {
+    let tree: Tree<u32, u32> = (0..10).map(|k| (k, k)).collect();
+    let rebuilt: Tree<u32, u32> = (0..10).map(|k| (k, k)).collect();
+    assert!(tree.ptr_eq(&tree.clone()));
+    assert!(!tree.ptr_eq(&rebuilt));
+    assert!(!tree.ptr_eq(&tree.insert(3, 3)));
+    assert!(Tree::<u32, u32>::new().ptr_eq(&Tree::new()));
+}
+
+#[test]
+fn replace_if_only_touches_matching_entries() {
+    let tree: Tree<u32, u32> = (0..10).map(|k| (k, k)).collect();
+    let bumped = tree.replace_if(4, |v| *v == 4, |v| v + 100);
+    assert_eq!(bumped.get(&4), Some(&104));
+    assert_eq!(tree.get(&4), Some(&4));
+    assert!(bumped.replace_if(4, |v| *v == 4, |v| v + 100).ptr_eq(&bumped));
+    assert!(tree.replace_if(42, |_| true, |v| v + 1).ptr_eq(&tree));
+}
+
+quickcheck! {
+    fn count_where_matches_filter(keys: Vec<u32>) -> bool {
+        let tree: Tree<u32, u32> = keys.into_iter().map(|k| (k, k / 2)).collect();
+        tree.count_where(|k, v| k % 3 == 0 && v % 2 == 0) ==
+            tree.iter().filter(|&(k, v)| k % 3 == 0 && v % 2 == 0).count()
+    }
+
+    fn count_values_where_matches_filter(keys: Vec<u32>, threshold: u32) -> bool {
+        let tree: Tree<u32, u32> = keys.into_iter().map(|k| (k, k / 2)).collect();
+        tree.count_values_where(|&v| v < threshold) ==
+            tree.iter().filter(|&(_, &v)| v < threshold).count()
+    }
+
+    fn chunks_concatenate_to_iter(keys: Vec<u32>, size: u8) -> bool {
+        let size = size as usize + 1;
+        let tree: Tree<u32, ()> = keys.into_iter().map(|k| (k, ())).collect();
+        let chunks: Vec<_> = tree.chunks(size).collect();
+        chunks.iter().all(|chunk| !chunk.is_empty() && chunk.len() <= size) &&
+            chunks.iter().rev().skip(1).all(|chunk| chunk.len() == size) &&
+            chunks.into_iter().flatten().eq(tree.iter())
+    }
+
+    fn iter_while_stops_at_first_false(keys: Vec<u8>, limit: u8) -> bool {
+        let (tree, _) = set_tree(keys);
+        let mut visited = vec![];
+        tree.iter_while(|&key, _| {
+            visited.push(key);
+            key < limit
+        });
+        let expected = tree.iter().map(|(&k, _)| k).take_while(|&k| k < limit).count();
+        visited.len() == cmp::min(expected + 1, tree.len()) &&
+            visited.iter().zip(tree.iter()).all(|(a, (b, _))| a == b)
+    }
+
+    fn into_binary_heap_pops_by_value_then_key(pairs: Vec<(u8, u8)>) -> bool {
+        let tree: Tree<u8, u8> = pairs.into_iter().collect();
+        let mut expected: Vec<(u8, u8)> = tree.iter().map(|(&k, &v)| (v, k)).collect();
+        expected.sort();
+        expected.reverse();
+        tree.into_binary_heap().into_sorted_vec().into_iter().rev().eq(expected)
+    }
+
+    fn value_histogram_counts_every_entry(pairs: Vec<(u8, u8)>) -> bool {
+        let tree: Tree<u8, u8> = pairs.into_iter().collect();
+        let histogram = tree.value_histogram(|value| value % 4);
+        histogram.values().sum::<usize>() == tree.len() &&
+            histogram.iter().all(|(&bucket, &count)| {
+                count == tree.count_where(|_, value| value % 4 == bucket)
+            })
+    }
+
+    fn into_iter_from_both_ends_meets_in_the_middle(keys: Vec<u8>, fronts: Vec<bool>) -> bool {
+        let (tree, _) = set_tree(keys);
+        let expected: Vec<u8> = tree.iter().map(|(&k, _)| k).collect();
+        let (mut iter, mut front, mut back) = (tree.into_iter(), vec![], vec![]);
+        for take_front in fronts.into_iter().chain(std::iter::repeat(true)) {
+            let len = iter.len();
+            let entry = if take_front { iter.next() } else { iter.next_back() };
+            match entry {
+                None => break,
+                Some((key, _)) if take_front => front.push(key),
+                Some((key, _)) => back.push(key),
+            }
+            if iter.len() != len - 1 {
+                return false
+            }
+        }
+        back.reverse();
+        front.into_iter().chain(back).eq(expected)
+    }
+
+    fn filter_keys_matches_iterator_filter(keys: Vec<u8>) -> bool {
+        let tree: Tree<u8, u8> = keys.into_iter().map(|key| (key, key)).collect();
+        let evens = tree.filter_keys(|&key| key % 2 == 0);
+        evens.check_invariants() && evens.iter().eq(tree.iter().filter(|&(&key, _)| key % 2 == 0))
+    }
+
+    fn iter_outside_in_pairs_ends(keys: Vec<u8>) -> bool {
+        let (tree, _) = set_tree(keys);
+        let entries: Vec<(&u8, &())> = tree.iter().collect();
+        let len = entries.len();
+        let mut expected: Vec<OutsideIn<u8, ()>> =
+            (0..len / 2).map(|i| OutsideIn::Pair(entries[i], entries[len - 1 - i])).collect();
+        if len % 2 == 1 {
+            expected.push(OutsideIn::Middle(entries[len / 2]))
+        }
+        tree.iter_outside_in().eq(expected)
+    }
+
+    fn distinct_value_count_matches_btreeset(entries: Vec<(u16, u8)>) -> bool {
+        let tree: Tree<u16, u8> = entries.into_iter().collect();
+        let values: BTreeSet<u8> = tree.iter().map(|(_, &v)| v).collect();
+        tree.distinct_value_count() == values.len()
+    }
+
+    fn group_into_partitions_by_group(keys: Vec<u16>) -> bool {
+        let tree: Tree<u16, ()> = keys.into_iter().map(|k| (k, ())).collect();
+        let grouped = tree.group_into(|&key, _| key % 7);
+        grouped.check_invariants() &&
+            grouped.iter().map(|(_, group)| group.len()).sum::<usize>() == tree.len() &&
+            grouped.iter().all(|(&group, inner)| {
+                !inner.is_empty() && inner.check_invariants() &&
+                    inner.iter().eq(tree.iter().filter(|&(&key, _)| key % 7 == group))
+            })
+    }
+}
+
+#[test]
+fn group_by_key_groups_only_adjacent_runs() {
+    let tree: Tree<u32, ()> = vec![1, 2, 11, 12, 13, 25].into_iter().map(|k| (k, ())).collect();
+    let groups: Vec<(u32, Vec<u32>)> = tree.group_by_key(|k| k / 10)
+                                           .map(|(g, es)| (g, es.iter().map(|e| *e.0).collect()))
+                                           .collect();
+    assert_eq!(groups, vec![(0, vec![1, 2]), (1, vec![11, 12, 13]), (2, vec![25])]);
+
+    let parity: Vec<bool> = tree.group_by_key(|k| k % 2 == 0).map(|(g, _)| g).collect();
+    assert_eq!(parity, vec![false, true, false, true, false]);
+}
+
+quickcheck! {
+    fn bounds_match_btreemap_range(keys: Vec<u8>, probe: u8) -> bool {
+        let (tree, reference) = set_tree(keys);
+        tree.lower_bound(&probe).map(|(k, _)| k) == reference.range(probe..).next() &&
+            tree.upper_bound(&probe).map(|(k, _)| k) ==
+                reference.range((Bound::Excluded(probe), Bound::Unbounded)).next()
+    }
+
+    fn get_path_descends_to_key(keys: Vec<u8>, probe: u8) -> bool {
+        let (tree, reference) = set_tree(keys);
+        let path = tree.get_path(&probe);
+        let found = path.last().map(|&(_, ordering)| ordering) == Some(cmp::Ordering::Equal);
+        path.len() <= tree.root.height() &&
+            path.iter().all(|&(key, ordering)| probe.cmp(key) == ordering) &&
+            found == reference.contains(&probe)
+    }
+
+    fn nearest_by_minimizes_distance(keys: Vec<u8>, probe: u8) -> bool {
+        let (tree, reference) = set_tree(keys);
+        let dist = |a: &u8, b: &u8| (*a as i16 - *b as i16).abs();
+        let best = reference.iter().map(|k| (dist(&probe, k), *k)).min();
+        tree.nearest_by(&probe, dist).map(|(k, _)| (dist(&probe, k), *k)) == best
+    }
+
+    fn get_bounded_agrees_with_get_path(keys: Vec<u8>, probe: u8, max_steps: u8) -> bool {
+        let tree: Tree<u8, u8> = keys.into_iter().map(|k| (k, k)).collect();
+        let steps = tree.get_path(&probe).len();
+        match tree.get_bounded(&probe, max_steps as usize) {
+            Ok(value) => steps <= max_steps as usize && value == tree.get(&probe),
+            Err(error) => {
+                steps > max_steps as usize && error == TooDeep { max_steps: max_steps as usize }
+            }
+        }
+    }
+
+    fn positions_of_matches_sorted_vec(keys: Vec<u8>, key: u8) -> bool {
+        let (tree, reference) = set_tree(keys);
+        let sorted: Vec<u8> = reference.into_iter().collect();
+        let expected = match sorted.binary_search(&key) {
+            Ok(position) => position..(position + 1),
+            Err(position) => position..position,
+        };
+        tree.positions_of(&key) == expected
+    }
+
+    fn position_at_or_after_finds_lower_bound(keys: Vec<u8>, key: u8) -> bool {
+        let (tree, reference) = set_tree(keys);
+        let position = tree.position_at_or_after(&key);
+        tree.select(position) == tree.lower_bound(&key) &&
+            position == reference.range(..key).count()
+    }
+
+    fn range_has_any_matches_btreeset(keys: Vec<u8>, start: u8, end: u8) -> bool {
+        let (tree, reference) = set_tree(keys);
+        let (start, end) = (cmp::min(start, end), cmp::max(start, end));
+        let bounds = (Bound::Excluded(start), Bound::Included(end));
+        tree.range_has_any(start..end) == reference.range(start..end).next().is_some() &&
+            tree.range_has_any(bounds) == reference.range(bounds).next().is_some() &&
+            tree.range_has_any(..) == reference.iter().next().is_some()
+    }
+
+    fn value_range_finds_run_of_equal_values(values: Vec<u8>, target: u8) -> bool {
+        let mut values = values;
+        values.sort();
+        let tree: Tree<usize, u8> = values.iter().cloned().enumerate().collect();
+        let expected = values.iter().position(|&value| value == target).map(|first| {
+            (first, values.iter().rposition(|&value| value == target).unwrap())
+        });
+        tree.value_range(&target).map(|(&first, &last)| (first, last)) == expected
+    }
+}
+
+struct CloneCounter(Rc<Cell<usize>>);
+
+impl Clone for CloneCounter {
+    fn clone(&self) -> CloneCounter {
+        self.0.set(self.0.get() + 1);
+        CloneCounter(self.0.clone())
+    }
+}
+
+fn counted_tree(len: u32) -> (Tree<u32, CloneCounter>, Rc<Cell<usize>>) {
+    let clones = Rc::new(Cell::new(0));
+    let tree = (0..len).map(|k| (k, CloneCounter(clones.clone()))).collect();
+    clones.set(0);
+    (tree, clones)
+}
+
+#[test]
+fn into_sorted_vec_moves_unshared_values() {
+    let (tree, clones) = counted_tree(100);
+    let keys: Vec<u32> = tree.into_sorted_vec().into_iter().map(|(k, _)| k).collect();
+    assert_eq!(keys, (0..100).collect::<Vec<_>>());
+    assert_eq!(clones.get(), 0);
+}
+
+#[test]
+fn into_sorted_vec_clones_shared_values() {
+    let (tree, clones) = counted_tree(100);
+    let shared = tree.clone();
+    assert_eq!(tree.into_sorted_vec().len(), 100);
+    assert_eq!(clones.get(), 100);
+    assert_eq!(shared.iter().count(), 100);
+}
+
+quickcheck! {
+    fn retain_range_matches_btreemap_range(keys: Vec<u8>, start: u8, end: u8) -> bool {
+        let (tree, reference) = set_tree(keys);
+        let (start, end) = (cmp::min(start, end), cmp::max(start, end));
+        let (half_open, closed) = (tree.retain_range(start..end), tree.retain_range(start..=end));
+        half_open.check_invariants() && closed.check_invariants() &&
+            half_open.iter().map(|(k, _)| k).eq(reference.range(start..end)) &&
+            closed.iter().map(|(k, _)| k).eq(reference.range(start..=end)) &&
+            tree.retain_range(..).iter().eq(tree.iter())
+    }
+
+    fn subtree_matches_btreeset_range(keys: Vec<u8>, start: u8, end: u8) -> bool {
+        let (tree, reference) = set_tree(keys);
+        let (start, end) = (cmp::min(start, end), cmp::max(start, end));
+        let bounds = (Bound::Excluded(start), Bound::Included(end));
+        let subtree = tree.subtree(bounds);
+        subtree.check_invariants() && subtree.iter().map(|(k, _)| k).eq(reference.range(bounds))
+    }
+
+    fn range_take_pages_from_start(keys: Vec<u8>, start: u8, n: u8) -> bool {
+        let (tree, reference) = set_tree(keys);
+        let page = tree.range_take(&start, n as usize).into_iter().map(|(k, _)| *k);
+        page.eq(reference.range(start..).take(n as usize).cloned())
+    }
+
+    fn range_aggregate_survives_rebalancing(edits: Vec<(u8, bool)>, start: u8, end: u8) -> bool {
+        let (mut tree, mut reference) = (Tree::<u8, (), Keys>::default(), BTreeSet::new());
+        for (key, insert) in edits {
+            if insert {
+                tree = tree.insert(key, ());
+                reference.insert(key);
+            } else {
+                tree = tree.remove(&key);
+                reference.remove(&key);
+            }
+        }
+        let (start, end) = (cmp::min(start, end), cmp::max(start, end));
+        tree.aggregate() == reference.iter().cloned().collect::<Vec<_>>() &&
+            tree.range_aggregate(start..end) ==
+                reference.range(start..end).cloned().collect::<Vec<_>>() &&
+            tree.range_aggregate((Bound::Excluded(start), Bound::Included(end))) ==
+                reference.range((Bound::Excluded(start), Bound::Included(end)))
+                    .cloned().collect::<Vec<_>>()
+    }
+
+    fn fold_range_matches_btreemap_range(pairs: Vec<(u8, u32)>, start: u8, end: u8) -> bool {
+        let reference: BTreeMap<u8, u32> = pairs.iter().cloned().collect();
+        let tree: Tree<u8, u32> = pairs.into_iter().collect();
+        let (start, end) = (cmp::min(start, end), cmp::max(start, end));
+        let sum = |sum: u64, _: &u8, value: &u32| sum + *value as u64;
+        let expected: u64 = reference.range(start..=end).map(|(_, &v)| v as u64).sum();
+        tree.fold_range(start..=end, 0, sum) == expected &&
+            tree.fold_range(start..end, vec![], |mut keys, &key, _| {
+                keys.push(key);
+                keys
+            }) == reference.range(start..end).map(|(&k, _)| k).collect::<Vec<_>>()
+    }
+
+    fn map_values_in_range_matches_btreemap(keys: Vec<u8>, lo: u8, hi: u8) -> bool {
+        let tree: Tree<u8, u32> = keys.iter().map(|&key| (key, key as u32)).collect();
+        let mapped = tree.map_values_in_range(lo..hi, |&key, &value| value + key as u32 + 1);
+        let mut reference: BTreeMap<u8, u32> = keys.iter().map(|&key| (key, key as u32)).collect();
+        if lo < hi {
+            for (&key, value) in reference.range_mut(lo..hi) {
+                *value += key as u32 + 1
+            }
+        }
+        mapped.check_invariants() && mapped.iter().eq(reference.iter())
+    }
+
+    fn range_values_vec_matches_btreemap(keys: Vec<u8>, lo: u8, hi: u8) -> bool {
+        let tree: Tree<u8, u32> = keys.iter().map(|&k| (k, k as u32 * 7)).collect();
+        if lo > hi {
+            return tree.range_values_vec(lo..=hi).is_empty() && tree.count_range(lo..=hi) == 0
+        }
+        let reference: BTreeMap<u8, u32> = keys.iter().map(|&k| (k, k as u32 * 7)).collect();
+        let expected: Vec<u32> = reference.range(lo..=hi).map(|(_, &v)| v).collect();
+        tree.range_values_vec(lo..=hi) == expected && tree.count_range(lo..=hi) == expected.len() &&
+            tree.count_range((Bound::Excluded(lo), Bound::Excluded(hi))) ==
+                reference.range(lo..=hi).filter(|&(&k, _)| k != lo && k != hi).count()
+    }
+
+    fn prefix_str_matches_filter(keys: Vec<(u8, u8)>, prefix: u8) -> bool {
+        // Keys over a three-letter alphabet, so that prefixes are often shared.
+        let letter = |byte: u8| (b'a' + byte % 3) as char;
+        let tree: Tree<String, ()> = keys.into_iter().map(|(first, second)| {
+            (vec![letter(first), letter(second)].into_iter().collect(), ())
+        }).collect();
+        let prefix = letter(prefix).to_string();
+        let reference = tree.iter().filter(|&(key, _)| key.starts_with(&prefix));
+        tree.prefix_str(&prefix).eq(reference) &&
+            tree.prefix_str("").eq(tree.iter())
+    }
+}
+
+#[test]
+fn insert_unique_hands_back_rejected_value() {
+    let tree = Tree::new().insert(1, "one");
+    let tree = tree.insert_unique(2, "two").unwrap();
+    assert_eq!(tree.get(&2), Some(&"two"));
+    match tree.insert_unique(1, "uno") {
+        Ok(_) => panic!("inserted a duplicate key"),
+        Err((unchanged, value)) => {
+            assert!(unchanged.ptr_eq(&tree));
+            assert_eq!(value, "uno");
+        }
+    }
+}
+
+#[test]
+fn approximate_heap_size_counts_only_unshared_nodes() {
+    let tree: Tree<u32, u32> = (0..100).map(|k| (k, k)).collect();
+    let size = tree.approximate_heap_size();
+    assert!(size >= 100 * 2 * mem::size_of::<u32>());
+    let updated = tree.insert(50, 0);
+    assert!(updated.approximate_heap_size() < size / 4);
+    let shared = tree.clone();
+    assert_eq!(shared.approximate_heap_size(), 0);
+    assert_eq!(Tree::<u32, u32>::new().approximate_heap_size(), 0);
+}
+
+#[test]
+fn iter_flat_expands_values_in_order() {
+    let tree: Tree<u32, Vec<char>> = vec![(2, vec!['c']), (1, vec!['a', 'b']), (3, vec![])]
+        .into_iter()
+        .collect();
+    let flat: Vec<(u32, char)> = tree.iter_flat().map(|(k, c)| (*k, *c)).collect();
+    assert_eq!(flat, vec![(1, 'a'), (1, 'b'), (2, 'c')]);
+}
+
+#[test]
+fn apply_replays_operation_log() {
+    let log = vec![Op::Insert(1, "a"), Op::Insert(2, "b"), Op::Remove(1), Op::Insert(2, "c")];
+    let tree = Tree::new().insert(3, "z").apply(log);
+    assert_eq!(tree.iter().collect::<Vec<_>>(), vec![(&2, &"c"), (&3, &"z")]);
+}
+
+impl<K, V> Arbitrary for Op<K, V> where K: Arbitrary, V: Arbitrary {
+    fn arbitrary<G>(g: &mut G) -> Op<K, V> where G: Gen {
+        if g.gen() {
+            Op::Insert(K::arbitrary(g), V::arbitrary(g))
+        } else {
+            Op::Remove(K::arbitrary(g))
+        }
+    }
+}
+
+// Applies `ops` in turn, checking the invariants and the entries against a `BTreeMap` after each,
+// as the fuzz target does.
+fn ops_agree_with_btreemap_throughout(ops: Vec<Op<u8, u8>>) -> bool {
+    let (mut tree, mut reference) = (Tree::new(), BTreeMap::new());
+    for op in ops {
+        match op {
+            Op::Insert(key, value) => {
+                tree = tree.insert(key, value);
+                reference.insert(key, value);
+            }
+            Op::Remove(key) => {
+                tree = tree.remove(&key);
+                reference.remove(&key);
+            }
+        }
+        if !tree.check_invariants() || !tree.iter().eq(reference.iter()) {
+            return false
+        }
+    }
+    true
+}
+
+quickcheck! {
+    fn split_at_divides_by_position(keys: Vec<u32>, index: usize) -> bool {
+        let keys: BTreeSet<u32> = keys.into_iter().collect();
+        let mut tree = Tree::new();
+        for &key in &keys {
+            tree = tree.insert(key, ());
+        }
+        let index = index % (keys.len() + 2);
+        let (before, after) = tree.split_at(index);
+        before.check_invariants() && after.check_invariants() &&
+            before.len() == cmp::min(index, keys.len()) &&
+            before.iter().chain(after.iter()).eq(tree.iter()) &&
+            tree.select(index).map(|(k, _)| k) == keys.iter().nth(index)
+    }
+
+    fn enumerate_entries_matches_select(keys: Vec<u8>) -> bool {
+        let tree: Tree<u8, u8> = keys.into_iter().map(|k| (k, k / 2)).collect();
+        tree.enumerate_entries().eq(tree.iter().enumerate().map(|(i, (k, v))| (i, k, v))) &&
+            tree.enumerate_entries().all(|(i, k, v)| tree.select(i) == Some((k, v)))
+    }
+
+    fn truncate_keeps_smallest_keys(keys: Vec<u8>, len: u8) -> bool {
+        let (tree, _) = set_tree(keys);
+        let truncated = tree.truncate(len as usize);
+        truncated.check_invariants() && truncated.iter().eq(tree.iter().take(len as usize))
+    }
+
+    fn keep_largest_keeps_largest_keys(keys: Vec<u8>, len: u8) -> bool {
+        let (tree, _) = set_tree(keys);
+        let kept = tree.keep_largest(len as usize);
+        let skip = tree.len().saturating_sub(len as usize);
+        kept.check_invariants() && kept.iter().eq(tree.iter().skip(skip))
+    }
+
+    fn rank_range_matches_skip_take(keys: Vec<u8>, lo: u8, hi: u8) -> bool {
+        let (tree, _) = set_tree(keys);
+        let (lo, hi) = (lo as usize, hi as usize);
+        tree.rank_range(lo, hi).eq(tree.iter().skip(lo).take(hi.saturating_sub(lo)))
+    }
+
+    fn bound_to_drops_the_requested_end(keys: Vec<u8>, cap: u8, drop_high: bool) -> bool {
+        let (tree, _) = set_tree(keys);
+        let bounded = tree.bound_to(cap as usize, drop_high);
+        let excess = tree.len().saturating_sub(cap as usize);
+        bounded.check_invariants() && bounded.len() == cmp::min(tree.len(), cap as usize) &&
+            if drop_high {
+                bounded.iter().eq(tree.iter().take(cap as usize))
+            } else {
+                bounded.iter().eq(tree.iter().skip(excess))
+            }
+    }
+
+    fn partition_into_covers_tree_evenly(keys: Vec<u8>, n: u8) -> bool {
+        let (tree, _) = set_tree(keys);
+        let n = n as usize % 20 + 1;
+        let partitions = tree.partition_into(n);
+        let (min, max) = (tree.len() / n, tree.len().div_ceil(n));
+        partitions.len() == n &&
+            partitions.iter().all(|p| p.check_invariants() && p.len() >= min && p.len() <= max) &&
+            partitions.iter().flat_map(|p| p.iter()).eq(tree.iter())
+    }
+
+    fn first_n_and_last_n_are_ascending_ends(keys: Vec<u8>, n: usize) -> bool {
+        let (tree, _) = set_tree(keys);
+        let n = n % (tree.len() + 2);
+        let entries: Vec<(&u8, &())> = tree.iter().collect();
+        let skipped = entries.len().saturating_sub(n);
+        tree.first_n(n) == entries.iter().cloned().take(n).collect::<Vec<_>>() &&
+            tree.last_n(n) == entries[skipped..].to_vec()
+    }
+
+    fn spine_ends_at_min_and_max(keys: Vec<u32>) -> bool {
+        let tree: Tree<u32, ()> = keys.into_iter().map(|key| (key, ())).collect();
+        let (left, right) = (tree.spine(false), tree.spine(true));
+        left.len() <= tree.root.height() && right.len() <= tree.root.height() &&
+            left.first() == right.first() &&
+            left.last() == tree.iter().next().as_ref() &&
+            right.last() == tree.iter().last().as_ref() &&
+            left.windows(2).all(|pair| pair[0].0 > pair[1].0) &&
+            right.windows(2).all(|pair| pair[0].0 < pair[1].0)
+    }
+}
+
+#[cfg(feature = "rayon")]
+quickcheck! {
+    fn par_iter_matches_iter(keys: Vec<u32>) -> bool {
+        use rayon::iter::ParallelIterator;
+        let tree: Tree<u32, u32> = keys.into_iter().map(|k| (k, k)).collect();
+        let entries: Vec<(&u32, &u32)> = tree.par_iter().collect();
+        entries == tree.iter().collect::<Vec<_>>()
+    }
+}
+
+#[cfg(feature = "rayon")]
+quickcheck! {
+    fn par_collect_keeps_last_duplicate(pairs: Vec<(u8, u32)>) -> bool {
+        use rayon::iter::{IntoParallelIterator, ParallelIterator};
+        let tree: Tree<u8, u32> = pairs.clone().into_par_iter().collect();
+        let reference: Tree<u8, u32> = pairs.into_iter().collect();
+        tree.check_invariants() && tree.iter().eq(reference.iter())
+    }
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn par_fold_matches_sequential_fold() {
+    let tree: Tree<u32, u32> = (0..10_000).map(|key| (key, key * 3)).collect();
+    let keys = tree.par_fold(Vec::new(), |mut keys, (&key, _)| {
+        keys.push(key);
+        keys
+    }, |mut left, right| {
+        left.extend(right);
+        left
+    });
+    assert!(keys.into_iter().eq(0..10_000));
+    let sum = tree.par_fold(0u64, |sum, (_, &value)| sum + value as u64, |a, b| a + b);
+    assert_eq!(sum, tree.iter().map(|(_, &value)| value as u64).sum::<u64>());
+}
+
+#[test]
+fn with_mut_edits_a_copy_of_the_value() {
+    let tree = Tree::new().insert("list", vec![1, 2]);
+    let (edited, len) = tree.with_mut("list", |list| {
+        list.push(3);
+        list.len()
+    });
+    assert_eq!(len, Some(3));
+    assert_eq!(edited.get("list"), Some(&vec![1, 2, 3]));
+    assert_eq!(tree.get("list"), Some(&vec![1, 2]));
+
+    let (unchanged, result) = tree.with_mut("missing", |list| list.push(4));
+    assert!(unchanged.ptr_eq(&tree) && result.is_none());
+}
+
+#[test]
+fn into_keys_and_values_move_unshared_entries() {
+    let (tree, clones) = counted_tree(100);
+    assert_eq!(tree.into_values().count(), 100);
+    assert_eq!(clones.get(), 0);
+
+    let (tree, clones) = counted_tree(100);
+    let shared = tree.insert(1000, CloneCounter(clones.clone()));
+    clones.set(0);
+    assert!(tree.into_keys().eq(0..100));
+    assert!(clones.get() > 0 && clones.get() < 100);
+    assert_eq!(shared.len(), 101);
+}
+
+// Summarizes a run of entries as their keys in order, which catches any misordered combine.
+struct Keys;
+
+impl Monoid<u8, ()> for Keys {
+    type Summary = Vec<u8>;
+
+    fn identity() -> Vec<u8> {
+        vec![]
+    }
+
+    fn lift(key: &u8, _: &()) -> Vec<u8> {
+        vec![*key]
+    }
+
+    fn combine(left: &Vec<u8>, right: &Vec<u8>) -> Vec<u8> {
+        left.iter().chain(right).cloned().collect()
+    }
+}
+
+#[cfg(feature = "rand")]
+#[test]
+fn sample_reaches_every_entry() {
+    let mut rng = rand::thread_rng();
+    assert_eq!(Tree::<u8, ()>::new().sample(&mut rng), None);
+    let tree: Tree<u8, ()> = (0..10).map(|k| (k, ())).collect();
+    let mut seen = BTreeSet::new();
+    for _ in 0..1000 {
+        seen.insert(*tree.sample(&mut rng).unwrap().0);
+    }
+    assert_eq!(seen.len(), 10);
+}
+
+#[cfg(feature = "rand")]
+quickcheck! {
+    fn sample_k_is_sorted_distinct_and_sized(keys: Vec<u8>, k: u8) -> bool {
+        let (tree, _) = set_tree(keys);
+        let sample = tree.sample_k(k as usize, &mut rand::thread_rng());
+        sample.len() == cmp::min(k as usize, tree.len()) &&
+            sample.windows(2).all(|pair| pair[0].0 < pair[1].0) &&
+            sample.iter().all(|&(key, _)| tree.get(key).is_some())
+    }
+}
+
+#[test]
+fn layered_get_prefers_later_layers() {
+    let base = Tree::new().insert(1, "old").insert(2, "base");
+    let layers = [base.clone(), base.insert(1, "new"), Tree::new()];
+    assert_eq!(Tree::layered_get(&layers, &1), Some(&"new"));
+    assert_eq!(Tree::layered_get(&layers, &2), Some(&"base"));
+    assert_eq!(Tree::layered_get(&layers, &3), None);
+}
+
+#[test]
+fn version_is_shared_only_by_identical_trees() {
+    let empty = Tree::new();
+    assert_eq!(empty.version(), 0);
+    let one = empty.insert(1, 1);
+    let two = one.insert(2, 2);
+    assert_eq!(one.clone().version(), one.version());
+    assert!(one.version() != 0 && one.version() != two.version());
+    assert!(two.remove(&2).version() != one.version());
+    assert_eq!(one.remove(&1).version(), 0);
+    assert_eq!(two.remove(&3).version(), two.version());
+}
+
+// Collects every node in a subtree.
+fn nodes<K, V>(link: &Link<K, V, ()>, nodes: &mut Vec<Arc<Node<K, V, ()>>>) {
+    if let Link::Node(ref node) = *link {
+        nodes.push(node.clone());
+        self::nodes(&node.left, nodes);
+        self::nodes(&node.right, nodes)
+    }
+}
+
+#[test]
+fn subtree_shares_nodes_with_original() {
+    let tree: Tree<u32, ()> = (0..1000).map(|k| (k, ())).collect();
+    let subtree = tree.subtree(100..900);
+    let (mut original, mut extracted) = (vec![], vec![]);
+    nodes(&tree.root, &mut original);
+    nodes(&subtree.root, &mut extracted);
+    let fresh = extracted.iter()
+                         .filter(|node| !original.iter().any(|other| Arc::ptr_eq(node, other)))
+                         .count();
+    assert_eq!(subtree.len(), 800);
+    assert!(fresh < 100, "{} of 800 nodes were copied", fresh);
+}
+
+#[test]
+fn from_level_order_rejects_invalid_structure() {
+    use Color::{Black, DoubleBlack, Red};
+    let valid = vec![(4, (), Black), (2, (), Black), (6, (), Black), (3, (), Red), (7, (), Red)];
+    assert!(Tree::<u8, ()>::from_level_order(valid).is_some());
+    // 3 must be a child of 2, but it comes after a child of 6.
+    let unordered =
+        vec![(4, (), Black), (2, (), Black), (6, (), Black), (7, (), Red), (3, (), Red)];
+    assert!(Tree::<u8, ()>::from_level_order(unordered).is_none());
+    let duplicate = vec![(2, (), Black), (2, (), Red)];
+    assert!(Tree::<u8, ()>::from_level_order(duplicate).is_none());
+    let red_red = vec![(2, (), Black), (1, (), Red), (0, (), Red)];
+    assert!(Tree::<u8, ()>::from_level_order(red_red).is_none());
+    let unbalanced = vec![(2, (), Black), (1, (), Black)];
+    assert!(Tree::<u8, ()>::from_level_order(unbalanced).is_none());
+    let double_black = vec![(1, (), DoubleBlack)];
+    assert!(Tree::<u8, ()>::from_level_order(double_black).is_none());
 }
 
+// A key whose `Ord` claims every pair is ascending in both directions, and so disagrees with its
+// `PartialOrd`.
+#[derive(Clone, PartialEq, Eq, PartialOrd)]
+struct AlwaysLess(u8);
+
+#[allow(clippy::derive_ord_xor_partial_ord)]
+impl cmp::Ord for AlwaysLess {
+    fn cmp(&self, _: &AlwaysLess) -> cmp::Ordering {
+        cmp::Ordering::Less
+    }
+}
+
+#[test]
+fn debug_check_key_order_catches_inconsistent_ord() {
+    let tree = Tree::new().insert(AlwaysLess(1), ()).insert(AlwaysLess(2), ());
+    assert!(!tree.debug_check_key_order());
+    assert!(Tree::new().insert(1, ()).insert(2, ()).debug_check_key_order());
+}
+
+#[test]
+fn get_or_insert_with_only_computes_default_on_miss() {
+    let tree = Tree::new().insert(1, "one");
+    let (same, value) = tree.get_or_insert_with(1, || panic!("default computed on a hit"));
+    assert!(same.ptr_eq(&tree));
+    assert_eq!(value, "one");
+    let (grown, value) = tree.get_or_insert_with(2, || "two");
+    assert_eq!(value, "two");
+    assert_eq!(grown.get(&2), Some(&"two"));
+    assert!(grown.check_invariants());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn debug_structure_shows_shape_and_colors() {
+    let tree = Tree::new().insert(2, ()).insert(1, ()).insert(3, ());
+    assert_eq!(tree.debug_structure(),
+               "2 Black depth 0\n  L 1 Red depth 1\n  R 3 Red depth 1\n");
+}
+
+#[test]
+fn coalesce_merges_adjacent_runs_left_to_right() {
+    // Extents as start => length, merged when one ends where the next begins.
+    let extents: Tree<u32, u32> =
+        vec![(0, 2), (2, 3), (5, 1), (7, 1), (8, 2)].into_iter().collect();
+    let merged = extents.coalesce(|start, len, next, next_len| {
+        if start + len == *next { Some(len + next_len) } else { None }
+    });
+    assert!(merged.check_invariants());
+    assert_eq!(merged.into_sorted_vec(), vec![(0, 6), (7, 3)]);
+}
+
+#[test]
+fn index_finds_present_keys() {
+    let tree = Tree::new().insert("one".to_string(), 1);
+    assert_eq!(tree["one"], 1);
+}
+
+#[test]
+#[should_panic(expected = "no entry found for key `\"two\"`")]
+fn index_panics_with_missing_key() {
+    let tree = Tree::new().insert("one".to_string(), 1);
+    let _ = tree["two"];
+}
+
+#[test]
+fn try_insert_reports_existing_and_rejected_values() {
+    let tree = Tree::new().try_insert(1, "one").unwrap();
+    let error = tree.try_insert(1, "uno").unwrap_err();
+    assert_eq!(error, OccupiedError { existing: "one", value: "uno" });
+    assert_eq!(error.to_string(), "failed to insert \"uno\", key is occupied by \"one\"");
+}
+
+#[test]
+fn upsert_counts_occurrences() {
+    let words = ["a", "b", "a", "c", "a", "b"];
+    let counts = words.iter().fold(Tree::new(), |counts, &word| {
+        counts.upsert(word, |count| count + 1, || 1)
+    });
+    assert!(counts.check_invariants());
+    assert_eq!(counts.into_sorted_vec(), vec![("a", 3), ("b", 2), ("c", 1)]);
+}
+
+thread_local!(static COMPARISONS: Cell<usize> = const { Cell::new(0) });
+
+// A key that counts how many times it is compared.
+#[derive(Clone, PartialEq, Eq)]
+struct CountedKey(u32);
+
+impl PartialOrd for CountedKey {
+    fn partial_cmp(&self, other: &CountedKey) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CountedKey {
+    fn cmp(&self, other: &CountedKey) -> cmp::Ordering {
+        COMPARISONS.with(|comparisons| comparisons.set(comparisons.get() + 1));
+        self.0.cmp(&other.0)
+    }
+}
+
+#[test]
+fn new_entries_since_skips_shared_subtrees() {
+    let base: Tree<CountedKey, ()> = (0..10000).map(|k| (CountedKey(k * 2), ())).collect();
+    let derived = base.insert(CountedKey(5001), ()).insert(CountedKey(15001), ());
+    COMPARISONS.with(|comparisons| comparisons.set(0));
+    let new: Vec<u32> = derived.new_entries_since(&base).map(|(key, _)| key.0).collect();
+    assert_eq!(new, vec![5001, 15001]);
+    let comparisons = COMPARISONS.with(|comparisons| comparisons.get());
+    assert!(comparisons < 2000, "{} comparisons", comparisons);
+}
+
+#[test]
+fn new_entries_since_is_lazy() {
+    let base: Tree<CountedKey, ()> = (0..1000).map(|k| (CountedKey(k * 2), ())).collect();
+    let derived = (0..1000).fold(base.clone(), |tree, k| tree.insert(CountedKey(k * 2 + 1), ()));
+    COMPARISONS.with(|comparisons| comparisons.set(0));
+    let first = derived.new_entries_since(&base).next().map(|(key, _)| key.0);
+    assert_eq!(first, Some(1));
+    let comparisons = COMPARISONS.with(|comparisons| comparisons.get());
+    assert!(comparisons < 200, "{} comparisons", comparisons);
+}
+
+#[test]
+fn into_iter_rev_moves_unshared_entries() {
+    let (tree, clones) = counted_tree(100);
+    assert!(tree.into_iter().rev().map(|(key, _)| key).eq((0..100).rev()));
+    assert_eq!(clones.get(), 0);
+}
+
+#[test]
+fn from_structure_checked_names_the_violation() {
+    use Color::{self, Black, DoubleBlack, Red};
+    let check = |entries: Vec<(u8, (), Color)>| {
+        Tree::<u8, ()>::from_structure_checked(entries).map(|tree| tree.len())
+    };
+    assert_eq!(check(vec![(2, (), Black), (1, (), Red), (3, (), Red)]), Ok(3));
+    assert_eq!(check(vec![(2, (), Black), (2, (), Red)]),
+               Err(StructureError::OrderingViolation(1)));
+    assert_eq!(check(vec![(5, (), Black), (7, (), Red), (3, (), Red)]),
+               Err(StructureError::OrderingViolation(2)));
+    assert_eq!(check(vec![(2, (), Red), (1, (), Red)]), Err(StructureError::RedRed(0)));
+    assert_eq!(check(vec![(2, (), Black), (1, (), Black)]),
+               Err(StructureError::UnequalBlackHeight(0)));
+    assert_eq!(check(vec![(2, (), Black), (1, (), DoubleBlack), (3, (), Black)]),
+               Err(StructureError::DoubleBlack(1)));
+}
+
+#[test]
+#[should_panic]
+fn set_at_panics_out_of_bounds() {
+    let _ = Tree::new().insert(1, 'a').set_at(1, 'b');
+}
+
+#[test]
+fn largest_shared_subtree_finds_common_structure() {
+    let base: Tree<u32, ()> = (0..1000).map(|k| (k, ())).collect();
+    assert_eq!(base.largest_shared_subtree(&base).map(|tree| tree.len()), Some(1000));
+    let edited = base.insert(2000, ());
+    let shared = edited.largest_shared_subtree(&base).unwrap();
+    assert!(shared.len() >= 400 && shared.len() < 1000);
+    assert!(shared.iter().all(|(key, _)| base.get(key).is_some()));
+    assert_eq!(base.largest_shared_subtree(&edited).map(|tree| tree.len()), Some(shared.len()));
+    let (mut base_nodes, mut shared_nodes) = (vec![], vec![]);
+    nodes(&base.root, &mut base_nodes);
+    nodes(&shared.root, &mut shared_nodes);
+    assert!(base_nodes.iter().any(|node| Arc::ptr_eq(node, &shared_nodes[0])));
+    let separate: Tree<u32, ()> = (0..1000).map(|k| (k, ())).collect();
+    assert!(separate.largest_shared_subtree(&base).is_none());
+    assert!(Tree::<u32, ()>::new().largest_shared_subtree(&base).is_none());
+}
+
+#[test]
+fn color_counts_sees_every_node() {
+    assert_eq!(Tree::<u8, ()>::new().color_counts(), (0, 0, 0));
+    let tree: Tree<u32, ()> = (0..100).fold(Tree::new(), |tree, key| tree.insert(key, ()));
+    let (red, black, double_black) = tree.color_counts();
+    assert!(red > 0 && black > 0);
+    assert_eq!((red + black, double_black), (100, 0));
+}
+
+#[test]
+fn map_values_in_range_shares_untouched_subtrees() {
+    let tree: Tree<u32, u32> = (0..1000).map(|key| (key, key)).collect();
+    let mapped = tree.map_values_in_range(10..20, |_, &value| value * 2);
+    assert_eq!(mapped.get(&15), Some(&30));
+    assert_eq!(mapped.get(&20), Some(&20));
+    let (mut original, mut rewritten) = (vec![], vec![]);
+    nodes(&tree.root, &mut original);
+    nodes(&mapped.root, &mut rewritten);
+    let shared = rewritten.iter().filter(|node| original.iter().any(|old| Arc::ptr_eq(node, old)));
+    assert!(shared.count() >= 1000 - 10 - 2 * tree.root.height());
+}
+
+#[test]
+fn try_from_iter_reports_first_duplicate() {
+    let tree = Tree::<u8, char>::try_from_iter(vec![(3, 'a'), (1, 'b'), (2, 'c')]).unwrap();
+    assert!(tree.check_invariants());
+    assert!(tree.iter().eq(vec![(&1, &'b'), (&2, &'c'), (&3, &'a')]));
+    let entries = vec![(5, 'a'), (1, 'b'), (7, 'c'), (7, 'd'), (1, 'e'), (5, 'f')];
+    assert_eq!(Tree::<u8, char>::try_from_iter(entries).map(|tree| tree.len()),
+               Err(DuplicateKey { key: 7 }));
+}
+
+#[test]
+fn median_is_lower_middle_entry() {
+    assert_eq!(Tree::<u8, ()>::new().median(), None);
+    let odd: Tree<u8, ()> = (1..6).map(|key| (key, ())).collect();
+    assert_eq!(odd.median(), Some((&3, &())));
+    let even = odd.insert(6, ());
+    assert_eq!(even.median(), Some((&3, &())));
+}
+
+#[test]
+fn split_when_starts_segments_at_gaps() {
+    let tree: Tree<u32, ()> = vec![1, 2, 3, 10, 11, 20].into_iter().map(|key| (key, ())).collect();
+    let segments = tree.split_when(|&prev, _, &key, _| key - prev > 5);
+    let keys: Vec<Vec<u32>> =
+        segments.iter().map(|segment| segment.iter().map(|(&key, _)| key).collect()).collect();
+    assert_eq!(keys, vec![vec![1, 2, 3], vec![10, 11], vec![20]]);
+    assert!(segments.iter().all(|segment| segment.check_invariants()));
+    assert!(Tree::<u32, ()>::new().split_when(|_, _, _, _| true).is_empty());
+}
+
+#[test]
+fn empty_is_usable_in_constants() {
+    const EMPTY: Tree<u8, u8> = Tree::empty();
+    let mut trees = [EMPTY; 4];
+    trees[2] = trees[2].insert(1, 1);
+    assert!(trees[0].is_empty() && trees[0].ptr_eq(&Tree::new()));
+    assert_eq!(trees[0].version(), Tree::<u8, u8>::new().version());
+    assert_eq!(trees[2].len(), 1);
+}
+
+#[test]
+fn get_cow_calls_default_only_on_miss() {
+    let tree = Tree::new().insert(1, String::from("one"));
+    let calls = Cell::new(0);
+    let default = || {
+        calls.set(calls.get() + 1);
+        String::from("none")
+    };
+    assert_eq!(tree.get_cow(&1, default), Cow::Borrowed("one"));
+    assert_eq!(calls.get(), 0);
+    match tree.get_cow(&2, default) {
+        Cow::Owned(value) => assert_eq!(value, "none"),
+        Cow::Borrowed(_) => panic!("a miss should be owned"),
+    }
+    assert_eq!(calls.get(), 1);
+}
+
+#[test]
+fn before_and_after_stop_at_the_ends() {
+    let tree: Tree<u8, char> = vec![(1, 'a'), (2, 'b'), (3, 'c')].into_iter().collect();
+    assert_eq!(tree.before(0), None);
+    assert_eq!(tree.before(1), Some((&1, &'a')));
+    assert_eq!(tree.after(1), Some((&3, &'c')));
+    assert_eq!(tree.after(2), None);
+    assert_eq!(tree.after(usize::MAX), None);
+}
+
+// Summarizes a map through `OrderedMap` alone, so that it can be run on a `Tree` and a `BTreeMap`.
+fn ordered_map_summary<T>(map: &T, lo: u8, hi: u8) -> (usize, Option<u32>, Vec<u8>, Vec<u8>)
+                          where T: OrderedMap<u8, u32> {
+    (map.len(),
+     map.get(&lo).cloned(),
+     map.iter().map(|(&k, _)| k).collect(),
+     map.range(lo..hi).map(|(&k, _)| k).collect())
+}
+
+#[test]
+fn intern_values_shares_equal_values() {
+    use Color;
+    let tree: Tree<u32, String> = (0..100).map(|key| (key, format!("{}", key % 3))).collect();
+    let interned = tree.intern_values();
+    assert!(interned.check_invariants());
+    assert!(interned.iter().map(|(k, v)| (k, &**v)).eq(tree.iter()));
+    let shape: Vec<(u32, Color)> =
+        tree.to_level_order().into_iter().map(|(key, _, color)| (key, color)).collect();
+    assert!(interned.to_level_order().into_iter().map(|(key, _, color)| (key, color)).eq(shape));
+    let (zero, three) = (interned.get(&0).unwrap(), interned.get(&3).unwrap());
+    assert!(Arc::ptr_eq(zero, three));
+    assert!(!Arc::ptr_eq(zero, interned.get(&1).unwrap()));
+}
+
+#[test]
+fn repair_rebuilds_invalid_trees() {
+    use Color::{Black, Red};
+    let valid: Tree<u32, ()> = (0..100).map(|key| (key, ())).collect();
+    assert!(valid.repair().ptr_eq(&valid));
+    // A bare red chain is ordered but unbalanced.
+    let chain = Link::node(Red, 1, (), Link::node(Red, 0, (), Link::Empty, Link::Empty),
+                           Link::node(Black, 2, (), Link::Empty, Link::Empty));
+    let repaired = Tree::<u32, ()>::from_root(chain).repair();
+    assert!(repaired.check_invariants());
+    assert!(repaired.iter().map(|(&k, _)| k).eq(0..3));
+    let unordered = Link::node(Black, 1, 'a', Link::node(Red, 2, 'b', Link::Empty, Link::Empty),
+                               Link::node(Red, 1, 'c', Link::Empty, Link::Empty));
+    let repaired = Tree::<u32, char>::from_root(unordered).repair();
+    assert!(repaired.check_invariants());
+    assert!(repaired.iter().eq(vec![(&1, &'c'), (&2, &'b')]));
+}
+
+#[test]
+fn zip_positional_aligns_by_rank() {
+    let tree: Tree<u8, char> = vec![(30, 'c'), (10, 'a'), (20, 'b')].into_iter().collect();
+    let zipped: Vec<_> = tree.zip_positional(&[1, 2]).collect();
+    assert_eq!(zipped, vec![(&10, &'a', &1), (&20, &'b', &2)]);
+    assert_eq!(tree.zip_positional(&[1, 2, 3, 4]).count(), 3);
+}
+
+#[test]
+fn key_ranges_collapses_runs() {
+    let tree: Tree<u8, ()> = vec![9, 1, 2, 3, 5, 6, 255].into_iter().map(|k| (k, ())).collect();
+    assert_eq!(tree.key_ranges(|&key| key + 1), vec![1..=3, 5..=6, 9..=9, 255..=255]);
+    assert!(Tree::<u8, ()>::new().key_ranges(|&key| key + 1).is_empty());
+}
+
+#[cfg(feature = "paranoid")]
+#[test]
+#[should_panic(expected = "`insert` produced a tree that violates the red-black invariants")]
+fn paranoid_insert_catches_malformed_tree() {
+    use Color::Black;
+    let unbalanced = Link::node(Black, 1, (), Link::node(Black, 0, (), Link::Empty, Link::Empty),
+                                Link::Empty);
+    let _ = Tree::<u32, ()>::from_root(unbalanced).insert(0, ());
+}
+
+#[cfg(feature = "paranoid")]
+#[test]
+#[should_panic(expected = "`upsert` produced a tree that violates the red-black invariants")]
+fn paranoid_upsert_catches_malformed_tree() {
+    use Color::Black;
+    let unbalanced = Link::node(Black, 1, 0, Link::node(Black, 0, 0, Link::Empty, Link::Empty),
+                                Link::Empty);
+    let _ = Tree::<u32, u32>::from_root(unbalanced).upsert(0, |count| count + 1, || 0);
+}
+
+#[test]
+fn rename_key_moves_value_and_overwrites() {
+    let tree: Tree<u8, char> = vec![(1, 'a'), (2, 'b'), (3, 'c')].into_iter().collect();
+    assert!(tree.rename_key(&1, 5).iter().eq(vec![(&2, &'b'), (&3, &'c'), (&5, &'a')]));
+    assert!(tree.rename_key(&1, 3).iter().eq(vec![(&2, &'b'), (&3, &'a')]));
+    assert!(tree.rename_key(&2, 2).iter().eq(tree.iter()));
+    assert!(tree.rename_key(&4, 5).ptr_eq(&tree));
+}
+
+#[test]
+fn longest_increasing_run_takes_first_longest() {
+    let tree = |values: Vec<u8>| -> Tree<usize, u8> { values.into_iter().enumerate().collect() };
+    assert_eq!(tree(vec![]).longest_increasing_run(), None);
+    assert_eq!(tree(vec![3, 2, 2, 1]).longest_increasing_run(), Some((&0, &0)));
+    assert_eq!(tree(vec![1, 2, 0, 4, 5, 6, 1, 2, 3, 4]).longest_increasing_run(), Some((&2, &5)));
+    assert_eq!(tree(vec![1, 2, 3, 0, 5, 6, 7, 8]).longest_increasing_run(), Some((&3, &7)));
+}
+
+#[test]
+fn insert_bounded_rejects_only_new_keys_when_full() {
+    let tree = Tree::new().insert_bounded(1, 'a', 2).unwrap().insert_bounded(2, 'b', 2).unwrap();
+    assert_eq!(tree.insert_bounded(3, 'c', 2).map(|tree| tree.len()), Err('c'));
+    let updated = tree.insert_bounded(1, 'z', 2).unwrap();
+    assert_eq!(updated.get(&1), Some(&'z'));
+    let shrunk = tree.insert_bounded(2, 'y', 1).unwrap();
+    assert_eq!(shrunk.get(&2), Some(&'y'));
+}